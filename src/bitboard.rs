@@ -0,0 +1,277 @@
+//! 8x8の盤面を黒と白の64bitで持つ
+//!
+//! bitの位置はMiBoardのpos(行*8+列)と同じ
+//! 方向の順番はget_flip_limitと同じ(左,右,上,下,右上,右下,左上,左下)
+use crate::{DekunobouBoard, WALL};
+
+/**a列(左端)を除く*/
+const NOT_LEFT:u64=0xfefefefefefefefe;
+/**h列(右端)を除く*/
+const NOT_RIGHT:u64=0x7f7f7f7f7f7f7f7f;
+
+#[derive(Clone,Copy,Default,PartialEq,Eq,Hash,Debug)]
+pub struct Bitboard{
+	pub black:u64,
+	pub white:u64,
+}
+/**dir方向に1マスずらす 盤外に出た石は消える*/
+#[inline]
+fn shift(b:u64,dir:usize)->u64{
+	match dir{
+		0=>(b>>1)&NOT_RIGHT,
+		1=>(b<<1)&NOT_LEFT,
+		2=>b>>8,
+		3=>b<<8,
+		4=>(b>>7)&NOT_LEFT,
+		5=>(b<<9)&NOT_LEFT,
+		6=>(b>>9)&NOT_RIGHT,
+		_=>(b<<7)&NOT_RIGHT,
+	}
+}
+/**立っているbitの位置を小さい順に*/
+pub fn bits(mut b:u64)->impl Iterator<Item=u16>{
+	std::iter::from_fn(move||{
+		if b==0{
+			return None;
+		}
+		let pos=b.trailing_zeros() as u16;
+		b&=b-1;
+		Some(pos)
+	})
+}
+/**splitmix64*/
+fn mix(mut x:u64)->u64{
+	x=x.wrapping_add(0x9e3779b97f4a7c15);
+	x=(x^(x>>30)).wrapping_mul(0xbf58476d1ce4e5b9);
+	x=(x^(x>>27)).wrapping_mul(0x94d049bb133111eb);
+	x^(x>>31)
+}
+impl Bitboard{
+	/**8x8の盤のマス 置けないマスがあればNone*/
+	pub fn from_cells(cells:&[u8])->Option<Self>{
+		if cells.len()!=64{
+			return None;
+		}
+		let mut b=Self::default();
+		for (i,c) in cells.iter().enumerate(){
+			match *c{
+				1=>b.black|=1<<i,
+				2=>b.white|=1<<i,
+				WALL=>return None,
+				_=>{},
+			}
+		}
+		Some(b)
+	}
+	/**dekunobouに渡す文字列*/
+	pub fn to_dekunobou(self)->DekunobouBoard{
+		DekunobouBoard((0..64).map(|i|(b'0'+self.cell(i)) as char).collect())
+	}
+	/**0:空き 1:黒 2:白*/
+	pub fn cell(&self,pos:u16)->u8{
+		if self.black>>pos&1==1{
+			1
+		}else if self.white>>pos&1==1{
+			2
+		}else{
+			0
+		}
+	}
+	/**(手番の石,相手の石)*/
+	#[inline]
+	pub fn sides(&self,is_black:bool)->(u64,u64){
+		if is_black{
+			(self.black,self.white)
+		}else{
+			(self.white,self.black)
+		}
+	}
+	pub fn empty(&self)->u64{
+		!(self.black|self.white)
+	}
+	/**置ける場所*/
+	pub fn legal_moves(&self,is_black:bool)->u64{
+		let (p,o)=self.sides(is_black);
+		let empty=self.empty();
+		let mut moves=0;
+		for dir in 0..8{
+			let mut t=shift(p,dir)&o;
+			for _ in 0..5{
+				t|=shift(t,dir)&o;
+			}
+			moves|=shift(t,dir)&empty;
+		}
+		moves
+	}
+	/**方向ごとに返せる石*/
+	pub fn flips_by_dir(&self,pos:u16,is_black:bool)->[u64;8]{
+		let (p,o)=self.sides(is_black);
+		let mut flips=[0u64;8];
+		for (dir,f) in flips.iter_mut().enumerate(){
+			let mut line=0;
+			let mut x=shift(1<<pos,dir);
+			while x&o!=0{
+				line|=x;
+				x=shift(x,dir);
+			}
+			if x&p!=0{
+				*f=line;
+			}
+		}
+		flips
+	}
+	/**posに置いて返せる石*/
+	pub fn flips(&self,pos:u16,is_black:bool)->u64{
+		self.flips_by_dir(pos,is_black).iter().fold(0,|a,f|a|f)
+	}
+	/**posに置いて返した石を返す 置けない場所でも石は置く*/
+	pub fn play(&mut self,pos:u16,is_black:bool)->u64{
+		let flips=self.flips(pos,is_black);
+		self.apply(pos,flips,is_black);
+		flips
+	}
+	pub fn apply(&mut self,pos:u16,flips:u64,is_black:bool){
+		if is_black{
+			self.black|=flips|1<<pos;
+			self.white&=!flips;
+		}else{
+			self.white|=flips|1<<pos;
+			self.black&=!flips;
+		}
+	}
+	/**黒石と白石の数*/
+	pub fn count(&self)->(u32,u32){
+		(self.black.count_ones(),self.white.count_ones())
+	}
+	/**置換表用のハッシュ*/
+	pub fn hash(&self,is_black:bool)->u64{
+		let h=mix(self.black)^mix(self.white^0x5555555555555555).rotate_left(17);
+		if is_black{
+			h
+		}else{
+			!h
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use rand::{Rng, SeedableRng};
+
+	use super::*;
+	use crate::{GameRules, MiBoard};
+
+	fn initial()->Bitboard{
+		MiBoard::new().to_bitboard().unwrap()
+	}
+	/**同じ盤面をマスの並びで持つ盤*/
+	fn grid(board:&MiBoard)->MiBoard{
+		MiBoard::Grid{
+			width:8,
+			height:8,
+			cells:board.cells().collect(),
+		}
+	}
+	/**パスも1手として数える*/
+	fn perft(b:&Bitboard,is_black:bool,depth:u32,passed:bool)->u64{
+		if depth==0{
+			return 1;
+		}
+		let moves=b.legal_moves(is_black);
+		if moves==0{
+			if passed{
+				return 1;
+			}
+			return perft(b,!is_black,depth-1,true);
+		}
+		bits(moves).map(|pos|{
+			let mut next=*b;
+			next.play(pos,is_black);
+			perft(&next,!is_black,depth-1,false)
+		}).sum()
+	}
+	#[test]
+	fn perft_from_initial_position(){
+		let b=initial();
+		let counts=(1..=7).map(|d|perft(&b,true,d,false)).collect::<Vec<_>>();
+		assert_eq!(counts,vec![4,12,56,244,1396,8200,55092]);
+	}
+	#[test]
+	fn shift_drops_stones_leaving_the_board(){
+		//h列から右 a列から左には出ない
+		assert_eq!(shift(1<<7,1),0);
+		assert_eq!(shift(1<<8,0),0);
+		assert_eq!(shift(1<<63,3),0);
+		assert_eq!(shift(1,2),0);
+		assert_eq!(shift(1<<15,4),0);
+		assert_eq!(shift(1<<8,6),0);
+		assert_eq!(shift(1<<8,7),0);
+		assert_eq!(shift(1<<15,5),0);
+		//盤の中では1マス動く
+		assert_eq!(shift(1<<27,0),1<<26);
+		assert_eq!(shift(1<<27,1),1<<28);
+		assert_eq!(shift(1<<27,2),1<<19);
+		assert_eq!(shift(1<<27,3),1<<35);
+		assert_eq!(shift(1<<27,4),1<<20);
+		assert_eq!(shift(1<<27,5),1<<36);
+		assert_eq!(shift(1<<27,6),1<<18);
+		assert_eq!(shift(1<<27,7),1<<34);
+	}
+	#[test]
+	fn flips_in_each_direction(){
+		let b=initial();
+		//d3(19)に黒を置くとd4(27)が返る
+		assert_eq!(b.flips(19,true),1<<27);
+		assert_eq!(b.flips_by_dir(19,true)[3],1<<27);
+		//置けない場所は何も返らない
+		assert_eq!(b.flips(0,true),0);
+		let mut next=b;
+		assert_eq!(next.play(19,true),1<<27);
+		assert_eq!(next.count(),(4,1));
+	}
+	#[test]
+	fn saved_board_is_read_back_as_a_bitboard(){
+		let json=serde_json::to_value(MiBoard::new()).unwrap();
+		assert_eq!(json["width"],8);
+		assert_eq!(json["cells"].as_array().unwrap().len(),64);
+		let board=serde_json::from_value::<MiBoard>(json).unwrap();
+		assert_eq!(board.to_bitboard(),Some(initial()));
+		//置けないマスのあるマップはマスの並びのまま
+		let mut map=vec!["b-------".to_owned();7];
+		map.push("w------ ".to_owned());
+		let board=MiBoard::from_map(&map).unwrap();
+		let board=serde_json::from_value::<MiBoard>(serde_json::to_value(&board).unwrap()).unwrap();
+		assert!(board.to_bitboard().is_none());
+		assert_eq!(board.cell(7,7),WALL);
+	}
+	#[test]
+	fn matches_grid_move_generation(){
+		let rules=GameRules::default();
+		let mut rng=rand::rngs::StdRng::seed_from_u64(1);
+		for _ in 0..50{
+			let mut board=MiBoard::new();
+			let mut is_black=true;
+			loop{
+				let b=board.to_bitboard().unwrap();
+				//マスを1つずつ見る方法と同じ手が置ける
+				let mut grid=grid(&board);
+				let moves=grid.legal_move_list(is_black,&rules);
+				assert_eq!(bits(b.legal_moves(is_black)).collect::<Vec<_>>(),moves);
+				if moves.is_empty(){
+					if grid.legal_move_list(!is_black,&rules).is_empty(){
+						break;
+					}
+					is_black = !is_black;
+					continue;
+				}
+				let pos=moves[rng.gen_range(0..moves.len())];
+				let (_,flip_count)=grid.put_stone_silent(pos,is_black,&rules);
+				let (_,bit_flip_count)=board.put_stone_silent(pos,is_black,&rules);
+				assert_eq!(flip_count,bit_flip_count);
+				assert_eq!(board.cells().collect::<Vec<_>>(),grid.cells().collect::<Vec<_>>());
+				assert!(board.to_bitboard().is_some());
+				is_black = !is_black;
+			}
+		}
+	}
+}
@@ -0,0 +1,202 @@
+//! エンジンより先に引く定石
+//!
+//! 1行に`盤面(dekunobou形式の64文字) 手番(黒0/白1) 手 重み`を書く
+//! 手は0-63の番号か"f5"のような座標 同じ局面に複数の手を書くと重みに応じて選ぶ
+//! 対称な局面(回転/反転の8通り)は同じ局面として扱う
+use std::collections::HashMap;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::DekunobouBoard;
+
+#[derive(Serialize,Deserialize,Debug)]
+pub struct BookConfig{
+	pub path:String,
+	/**何手目まで定石を使うか*/
+	pub max_ply:usize,
+}
+/**8通りの対称変換でposを移す*/
+fn transform(sym:usize,pos:u8)->u8{
+	let r=pos/8;
+	let c=pos%8;
+	let (r,c)=match sym{
+		0=>(r,c),
+		1=>(c,7-r),
+		2=>(7-r,7-c),
+		3=>(7-c,r),
+		4=>(r,7-c),
+		5=>(7-r,c),
+		6=>(c,r),
+		_=>(7-c,7-r),
+	};
+	r*8+c
+}
+/**transformの逆変換*/
+fn inverse(sym:usize)->usize{
+	match sym{
+		1=>3,
+		3=>1,
+		s=>s,
+	}
+}
+fn transform_board(sym:usize,board:&str)->String{
+	let src=board.as_bytes();
+	let mut dst=vec![b'0';64];
+	for pos in 0..64u8{
+		dst[transform(sym,pos) as usize]=src[pos as usize];
+	}
+	String::from_utf8(dst).unwrap()
+}
+/**対称な局面のうち辞書順で最小のものとそこへの変換*/
+fn canonical(board:&str)->(String,usize){
+	(0..8).map(|sym|(transform_board(sym,board),sym)).min().unwrap()
+}
+fn parse_move(s:&str)->Option<u8>{
+	if let Ok(pos)=s.parse::<u8>(){
+		return if pos<64{
+			Some(pos)
+		}else{
+			None
+		};
+	}
+	let mut chars=s.chars();
+	let col=chars.next()?.to_ascii_lowercase();
+	let row=chars.next()?.to_digit(10)?;
+	if chars.next().is_some()||!('a'..='h').contains(&col)||!(1..=8).contains(&row){
+		return None;
+	}
+	Some((row as u8-1)*8+(col as u8-b'a'))
+}
+pub struct OpeningBook{
+	/**(正規化した盤面,手番)ごとの(正規化した手,重み)*/
+	entries:HashMap<(String,u8),Vec<(u8,u32)>>,
+	pub max_ply:usize,
+}
+impl OpeningBook{
+	pub fn load(config:&BookConfig)->std::io::Result<Self>{
+		let text=std::fs::read_to_string(&config.path)?;
+		let mut entries:HashMap<(String,u8),Vec<(u8,u32)>>=HashMap::new();
+		for (i,line) in text.lines().enumerate(){
+			let line=line.trim();
+			if line.is_empty()||line.starts_with('#'){
+				continue;
+			}
+			let cols=line.split_whitespace().collect::<Vec<_>>();
+			let parsed=match cols.as_slice(){
+				[board,turn,pos,rest@..] if board.len()==64=>{
+					let weight=rest.first().map(|w|w.parse::<u32>().ok()).unwrap_or(Some(1));
+					match (turn.parse::<u8>().ok().filter(|t|*t<2),parse_move(pos),weight){
+						(Some(turn),Some(pos),Some(weight))=>Some((*board,turn,pos,weight)),
+						_=>None,
+					}
+				},
+				_=>None,
+			};
+			match parsed{
+				Some((board,turn,pos,weight))=>{
+					let (key,sym)=canonical(board);
+					let moves=entries.entry((key,turn)).or_default();
+					let pos=transform(sym,pos);
+					match moves.iter_mut().find(|(p,_)|*p==pos){
+						Some(m)=>m.1+=weight,
+						None=>moves.push((pos,weight)),
+					}
+				},
+				None=>{
+					tracing::warn!(line=i+1,"book parse error: {}",line);
+				}
+			}
+		}
+		tracing::info!(positions=entries.len(),"book loaded");
+		Ok(Self{
+			entries,
+			max_ply:config.max_ply,
+		})
+	}
+	/**定石にあれば重みに応じて手を選ぶ*/
+	pub fn lookup(&self,board:&DekunobouBoard,is_black:bool)->Option<u8>{
+		let (key,sym)=canonical(&board.0);
+		let turn=if is_black{
+			0
+		}else{
+			1
+		};
+		let moves=self.entries.get(&(key,turn))?;
+		let total:u32=moves.iter().map(|(_,w)|*w).sum();
+		if total==0{
+			return None;
+		}
+		let mut r=rand::thread_rng().gen_range(0..total);
+		for (pos,weight) in moves{
+			if r<*weight{
+				return Some(transform(inverse(sym),*pos));
+			}
+			r-=weight;
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::{GameRules, MiBoard};
+
+	#[test]
+	fn transform_round_trips(){
+		for sym in 0..8{
+			let mut seen=[false;64];
+			for pos in 0..64u8{
+				let moved=transform(sym,pos);
+				assert!(moved<64);
+				seen[moved as usize]=true;
+				assert_eq!(transform(inverse(sym),moved),pos,"sym {} pos {}",sym,pos);
+			}
+			//全てのマスに1度ずつ移る
+			assert!(seen.iter().all(|s|*s));
+		}
+	}
+	#[test]
+	fn symmetric_boards_share_a_canonical_form(){
+		let board="1000000000000000000000000002100000012000000000000000000000000002";
+		let (key,_)=canonical(board);
+		for sym in 0..8{
+			assert_eq!(canonical(&transform_board(sym,board)).0,key);
+		}
+	}
+	#[test]
+	fn parse_moves(){
+		assert_eq!(parse_move("f5"),Some(37));
+		assert_eq!(parse_move("A1"),Some(0));
+		assert_eq!(parse_move("h8"),Some(63));
+		assert_eq!(parse_move("19"),Some(19));
+		assert_eq!(parse_move("64"),None);
+		assert_eq!(parse_move("i1"),None);
+		assert_eq!(parse_move("a9"),None);
+		assert_eq!(parse_move("a10"),None);
+	}
+	#[test]
+	fn lookup_in_a_transformed_position(){
+		//f5 d6と進んだ対称性のない局面
+		let rules=GameRules::default();
+		let mut board=MiBoard::new();
+		board.put_stone_silent(37,true,&rules);
+		board.put_stone_silent(43,false,&rules);
+		let board=board.to_dekunobou().unwrap();
+		assert_eq!((0..8).map(|sym|transform_board(sym,&board.0)).collect::<std::collections::HashSet<_>>().len(),8);
+		let path=std::env::temp_dir().join(format!("dekunobou_book_{}.txt",std::process::id()));
+		std::fs::write(&path,format!("# test\n{} 0 c3\n",board.0)).unwrap();
+		let book=OpeningBook::load(&BookConfig{
+			path:path.to_str().unwrap().to_owned(),
+			max_ply:10,
+		}).unwrap();
+		let _=std::fs::remove_file(&path);
+		for sym in 0..8{
+			let moved=DekunobouBoard(transform_board(sym,&board.0));
+			assert_eq!(book.lookup(&moved,true),Some(transform(sym,18)),"sym {}",sym);
+			//手番が違えば引かない
+			assert_eq!(book.lookup(&moved,false),None);
+		}
+	}
+}
@@ -0,0 +1,128 @@
+//! 1手ごとの持ち時間の管理
+//!
+//! 探索にかかった時間を見て持ち時間が足りなくなりそうなら読みを浅くする
+use serde::{Deserialize, Serialize};
+
+use crate::strength::StrengthProfile;
+
+/**通信の遅れを見込んで持ち時間から引く(ミリ秒)*/
+const MARGIN_MILLIS:u64=1500;
+/**これより速く返ってきたら読みを戻す(持ち時間に対する割合)*/
+const FAST_RATIO:f64=0.1;
+/**これより遅かったら読みを浅くする*/
+const SLOW_RATIO:f64=0.5;
+
+#[derive(Clone,Default,Serialize,Deserialize,Debug)]
+pub struct TimeManager{
+	/**対局のtimeLimitForEachTurn(ミリ秒)*/
+	turn_limit:Option<u64>,
+	/**前回の探索にかかった時間(ミリ秒)*/
+	last_elapsed:Option<u64>,
+	/**読みを浅くしている段数*/
+	depth_cut:u32,
+}
+impl TimeManager{
+	/**timeLimitForEachTurn(秒)を設定する*/
+	pub fn set_turn_limit(&mut self,secs:Option<u64>){
+		self.turn_limit=secs.filter(|s|*s>0).map(|s|s*1000);
+	}
+	/**この手に使える時間(ミリ秒) move_time_limit(秒)と対局の持ち時間の短い方*/
+	pub fn budget(&self,move_time_limit:Option<u64>)->Option<u64>{
+		let turn=self.turn_limit.map(|limit|limit.saturating_sub(MARGIN_MILLIS).max(limit/2));
+		match (turn,move_time_limit.map(|s|s*1000)){
+			(Some(a),Some(b))=>Some(a.min(b)),
+			(a,b)=>a.or(b),
+		}
+	}
+	/**前回の探索時間から今回の読みの深さを決める*/
+	pub fn profile(&mut self,profile:&StrengthProfile,move_time_limit:Option<u64>)->StrengthProfile{
+		if let (Some(budget),Some(elapsed))=(self.budget(move_time_limit),self.last_elapsed){
+			let ratio=elapsed as f64/budget.max(1) as f64;
+			if ratio>SLOW_RATIO&&self.depth_cut+1<profile.depth.max(profile.perfect_search_depth){
+				self.depth_cut+=1;
+				tracing::info!(depth_cut=self.depth_cut,elapsed,budget,"short on time, searching shallower");
+			}else if ratio<FAST_RATIO&&self.depth_cut>0{
+				self.depth_cut-=1;
+			}
+		}
+		let cut=|depth:u32|depth.saturating_sub(self.depth_cut).max(1);
+		StrengthProfile{
+			depth:cut(profile.depth),
+			perfect_search_depth:cut(profile.perfect_search_depth),
+			handicap:profile.handicap,
+		}
+	}
+	/**探索にかかった時間を記録する*/
+	pub fn record(&mut self,elapsed_millis:u64){
+		self.last_elapsed=Some(elapsed_millis);
+	}
+	/**時間切れで探索を打ち切った 次はprofileで1段浅くする*/
+	pub fn timed_out(&mut self,budget_millis:u64){
+		//かかった時間を持ち時間いっぱいとして記録すればprofileが読みを浅くする
+		self.last_elapsed=Some(budget_millis);
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	fn profile()->StrengthProfile{
+		StrengthProfile{
+			depth:8,
+			perfect_search_depth:12,
+			handicap:0.0,
+		}
+	}
+	#[test]
+	fn budget_takes_the_shorter_limit(){
+		let mut clock=TimeManager::default();
+		assert_eq!(clock.budget(None),None);
+		assert_eq!(clock.budget(Some(5)),Some(5000));
+		clock.set_turn_limit(Some(10));
+		assert_eq!(clock.budget(None),Some(10000-MARGIN_MILLIS));
+		assert_eq!(clock.budget(Some(5)),Some(5000));
+		//短い持ち時間でも半分は使う
+		clock.set_turn_limit(Some(2));
+		assert_eq!(clock.budget(None),Some(1000));
+		//0は持ち時間なし
+		clock.set_turn_limit(Some(0));
+		assert_eq!(clock.budget(None),None);
+	}
+	#[test]
+	fn slow_searches_cut_depth_and_fast_ones_restore_it(){
+		let mut clock=TimeManager::default();
+		let base=profile();
+		assert_eq!(clock.profile(&base,Some(10)).depth,8);
+		clock.record(6000);
+		let p=clock.profile(&base,Some(10));
+		assert_eq!((p.depth,p.perfect_search_depth),(7,11));
+		//間の時間なら変えない
+		clock.record(3000);
+		assert_eq!(clock.profile(&base,Some(10)).depth,7);
+		clock.record(500);
+		assert_eq!(clock.profile(&base,Some(10)).depth,8);
+	}
+	#[test]
+	fn timeout_cuts_depth_but_not_below_one(){
+		let mut clock=TimeManager::default();
+		//1回の時間切れでは1段だけ浅くする
+		clock.timed_out(10000);
+		assert_eq!(clock.profile(&profile(),Some(10)).depth,7);
+		let mut clock=TimeManager::default();
+		let base=StrengthProfile{
+			depth:2,
+			perfect_search_depth:1,
+			handicap:0.0,
+		};
+		for _ in 0..5{
+			clock.timed_out(10000);
+			let p=clock.profile(&base,Some(10));
+			assert_eq!((p.depth,p.perfect_search_depth),(1,1));
+		}
+		//続けて時間切れしても浅くする段数は積み上がらない
+		assert_eq!(clock.depth_cut,1);
+		clock.record(500);
+		assert_eq!(clock.profile(&base,Some(10)).depth,2);
+	}
+}
@@ -0,0 +1,146 @@
+//! メンションやダイレクト投稿で受け付けるコマンド
+//!
+//! 返事は元のノートへの返信として投稿する
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::{note, stats, strength, BotState, ConfigFile, MiChannel, MiUser, WSState, WSStream};
+
+#[derive(Serialize,Deserialize,Debug,Default)]
+#[serde(default)]
+pub struct CommandConfig{
+	/**pause/resume/reloadを使えるユーザーid*/
+	pub admins:Vec<String>,
+}
+/**mainチャンネルのmentionで届くノート*/
+#[derive(Serialize,Deserialize,Debug)]
+struct MiNote{
+	id:String,
+	text:Option<String>,
+	user:MiUser,
+	visibility:String,
+}
+enum Command{
+	Play,
+	Stronger,
+	Weaker,
+	Stats,
+	Resign,
+	Pause,
+	Resume,
+	Reload,
+	Help,
+}
+/**メンションを除いた本文からコマンドを読む*/
+fn parse(text:&str)->Command{
+	let words=text.split_whitespace().filter(|w|!w.starts_with('@')).collect::<Vec<_>>().join(" ").to_lowercase();
+	match words.as_str(){
+		"play"|"play me"|"対局"|"対局して"=>Command::Play,
+		"stronger"|"強く"=>Command::Stronger,
+		"weaker"|"弱く"=>Command::Weaker,
+		"stats"|"成績"=>Command::Stats,
+		"resign"|"resign current game"|"投了"=>Command::Resign,
+		"pause"|"pause invites"=>Command::Pause,
+		"resume"|"resume invites"=>Command::Resume,
+		"reload"|"reload config"=>Command::Reload,
+		_=>Command::Help,
+	}
+}
+/**次の対局からの強さをdelta段階変える*/
+async fn adjust_strength(config:&ConfigFile,state:&BotState,user:&MiUser,delta:i32)->String{
+	let mut offsets=state.strength_offsets.lock().await;
+	let offset=offsets.entry(user.id.clone()).or_insert(0);
+	*offset=(*offset+delta).clamp(-strength::MAX_OFFSET,strength::MAX_OFFSET);
+	let profile=strength::adjust(&strength::select(config,Some(user)),*offset);
+	format!("次の対局から読みの深さを{}にします",profile.depth)
+}
+const HELP:&str="コマンド: play me(対局を申し込む) / stronger / weaker / stats / resign current game(対局中のゲームを投了)";
+/**管理者にだけ見せるコマンド*/
+const ADMIN_HELP:&str="管理者: pause invites(招待の受け付けを止める) / resume invites / reload config";
+async fn handle(con:&Arc<WSStream>,client:&Client,state:&Arc<BotState>,note:&MiNote)->String{
+	let config=state.config();
+	let user=&note.user;
+	let is_admin=config.command.as_ref().map(|c|c.admins.contains(&user.id)).unwrap_or(false);
+	match parse(note.text.as_deref().unwrap_or("")){
+		Command::Play=>{
+			//始まるのは相手が受けてからなので枠は空きがあるかだけ見る
+			let room=state.game_slots.reserve(config.policy.max_concurrent_games).map(drop);
+			if let Err(reason)=room.and(crate::policy::check(&config,&state.invite_history,user).await){
+				return format!("今は対局できません ({})",reason);
+			}
+			//対局が始まったら結果をこのノートに返信する
+			state.play_requests.lock().await.insert(user.id.clone(),note.id.clone());
+			crate::sparring::invite(&config,con,client,state,&user.id).await;
+			"対局を申し込みました".to_owned()
+		},
+		Command::Stronger=>adjust_strength(&config,state,user,1).await,
+		Command::Weaker=>adjust_strength(&config,state,user,-1).await,
+		Command::Stats=>{
+			let host=user.host_or_instance(&config.instance);
+			let stats=state.stats.lock().await;
+			match stats.get(&user.id,host.as_deref()){
+				Some(player)=>format!("あなたとの成績: {} (botのレーティング{:.0})",stats::summary(player),stats.rating),
+				None=>format!("まだ対局していません (botのレーティング{:.0})",stats.rating),
+			}
+		},
+		Command::Resign=>{
+			let count=state.request_surrender_against(&user.id).await;
+			if count==0{
+				"あなたとの対局はありません".to_owned()
+			}else{
+				"投了します".to_owned()
+			}
+		},
+		Command::Pause|Command::Resume|Command::Reload if !is_admin=>{
+			"管理者だけが使えるコマンドです".to_owned()
+		},
+		Command::Pause=>{
+			state.paused.store(true,Ordering::SeqCst);
+			"招待の受け付けを止めました".to_owned()
+		},
+		Command::Resume=>{
+			state.paused.store(false,Ordering::SeqCst);
+			"招待の受け付けを再開しました".to_owned()
+		},
+		Command::Reload=>{
+			match state.reload_config(){
+				Ok(_)=>"設定を読み直しました".to_owned(),
+				Err(e)=>format!("設定を読み直せませんでした {}",e),
+			}
+		},
+		Command::Help if is_admin=>format!("{}\n{}",HELP,ADMIN_HELP),
+		Command::Help=>HELP.to_owned(),
+	}
+}
+pub async fn run(con:Arc<WSStream>,client:Client,state:Arc<BotState>){
+	let mut ws=WSState::new(con.clone()).await.unwrap();
+	let (s,mut r)=tokio::sync::mpsc::channel(2);
+	ws.open_channel(s,MiChannel::Main,None).await.unwrap();
+	while let Some(event)=r.recv().await{
+		if event.t!="mention"{
+			continue;
+		}
+		let note=match serde_json::value::from_value::<MiNote>(event.body){
+			Ok(note)=>note,
+			Err(e)=>{
+				tracing::warn!("note parse error {:?}",e);
+				continue;
+			}
+		};
+		//自分やbotとの応答が続かないようにする
+		if note.user.id==state.self_id||note.user.is_bot{
+			continue;
+		}
+		tracing::info!(user=%note.user.username,note=%note.id,"command {:?}",note.text);
+		let reply=handle(&con,&client,&state,&note).await;
+		let visible_user_ids=if note.visibility=="specified"{
+			vec![note.user.id.clone()]
+		}else{
+			vec![]
+		};
+		note::post(&state.config(),&client,reply,Some(note.id.clone()),&note.visibility,visible_user_ids).await;
+	}
+}
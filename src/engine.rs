@@ -0,0 +1,248 @@
+//! 手を選ぶエンジン
+//!
+//! 定石 → dekunobou → 手元の探索のように順に試して最初に手を返したものを使う
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+use rand::Rng;
+use reqwest::Client;
+
+use crate::{book, pool, search, GameRules, MiBoard};
+
+/**探索する局面*/
+pub struct Position<'a>{
+	pub game_id:&'a str,
+	pub board:&'a MiBoard,
+	pub is_black:bool,
+	pub rules:&'a GameRules,
+	/**これまでの手数*/
+	pub ply:usize,
+}
+pub struct Limits{
+	pub depth:u32,
+	pub perfect_search_depth:u32,
+	/**この手に使える時間(ミリ秒)*/
+	pub time_millis:Option<u64>,
+}
+#[derive(Debug)]
+pub struct SearchResult{
+	pub pos:u16,
+	/**手番側から見た評価値*/
+	pub eval:Option<i32>,
+	/**読み筋*/
+	pub pv:Vec<u16>,
+	/**手を返したエンジンの名前*/
+	pub engine:String,
+}
+impl SearchResult{
+	pub fn new(pos:u16,engine:&str)->Self{
+		Self{
+			pos,
+			eval:None,
+			pv:vec![],
+			engine:engine.to_owned(),
+		}
+	}
+}
+pub trait Engine:Send+Sync{
+	fn name(&self)->String;
+	/**手が返せなければNone 次のエンジンに任せる*/
+	fn search<'a>(&'a self,position:&'a Position<'a>,limits:&'a Limits)->BoxFuture<'a,Option<SearchResult>>;
+	/**対局の待っている探索を取り消す*/
+	fn cancel(&self,_game_id:&str){}
+}
+/**dekunobouが探索できる通常ルールの8x8の盤面*/
+fn dekunobou_board(position:&Position)->Option<crate::DekunobouBoard>{
+	if !position.rules.is_standard(){
+		return None;
+	}
+	position.board.to_dekunobou()
+}
+pub struct BookEngine(pub book::OpeningBook);
+impl Engine for BookEngine{
+	fn name(&self)->String{
+		"book".to_owned()
+	}
+	fn search<'a>(&'a self,position:&'a Position<'a>,_limits:&'a Limits)->BoxFuture<'a,Option<SearchResult>>{
+		Box::pin(async move{
+			if position.ply>=self.0.max_ply{
+				return None;
+			}
+			let pos=self.0.lookup(&dekunobou_board(position)?,position.is_black)? as u16;
+			if !position.board.legal_move_list(position.is_black,position.rules).contains(&pos){
+				tracing::warn!(pos,"illegal book move");
+				return None;
+			}
+			Some(SearchResult::new(pos,"book"))
+		})
+	}
+}
+/**dekunobouをFFIで呼ぶ*/
+pub struct FfiEngine(pub pool::EnginePool);
+impl Engine for FfiEngine{
+	fn name(&self)->String{
+		"ffi".to_owned()
+	}
+	fn search<'a>(&'a self,position:&'a Position<'a>,limits:&'a Limits)->BoxFuture<'a,Option<SearchResult>>{
+		Box::pin(async move{
+			let board=dekunobou_board(position)?;
+			let pos=self.0.search(position.game_id,&board,position.is_black,limits.depth,limits.perfect_search_depth).await?;
+			Some(SearchResult::new(pos as u16,"ffi"))
+		})
+	}
+	fn cancel(&self,game_id:&str){
+		self.0.cancel(game_id);
+	}
+}
+/**待つのをやめたら読みかけの探索を止める*/
+struct CancelOnDrop(Arc<AtomicBool>);
+impl Drop for CancelOnDrop{
+	fn drop(&mut self){
+		self.0.store(true,Ordering::Relaxed);
+	}
+}
+/**変則ルールや大きなマップでも使える手元の探索*/
+pub struct LocalEngine;
+impl Engine for LocalEngine{
+	fn name(&self)->String{
+		"local".to_owned()
+	}
+	fn search<'a>(&'a self,position:&'a Position<'a>,limits:&'a Limits)->BoxFuture<'a,Option<SearchResult>>{
+		Box::pin(async move{
+			let board=position.board.clone();
+			let rules=*position.rules;
+			let is_black=position.is_black;
+			let depth=limits.depth;
+			let endgame_empties=limits.perfect_search_depth;
+			//前のエンジンが時間を使っているかもしれないので余裕を残す
+			let time_millis=limits.time_millis.map(|t|t*8/10);
+			//探索中はスレッドを占有するのでtokioのワーカーでは読まない
+			//時間切れや対局の終わりでこのfutureが捨てられたらspawn_blockingの探索も止める
+			let cancel=CancelOnDrop(Arc::new(AtomicBool::new(false)));
+			let flag=cancel.0.clone();
+			let span=tracing::Span::current();
+			let outcome=tokio::task::spawn_blocking(move||span.in_scope(||search::best_move(&board,is_black,&rules,depth,endgame_empties,time_millis,Some(flag)))).await.ok()??;
+			drop(cancel);
+			tracing::debug!(depth=outcome.depth,eval=outcome.eval,"local search done");
+			Some(SearchResult{
+				pos:outcome.pos,
+				eval:Some(outcome.eval),
+				pv:outcome.pv,
+				engine:"local".to_owned(),
+			})
+		})
+	}
+}
+/**一番多く返せる手 ロセオなら一番少ない手*/
+pub struct GreedyEngine;
+impl Engine for GreedyEngine{
+	fn name(&self)->String{
+		"greedy".to_owned()
+	}
+	fn search<'a>(&'a self,position:&'a Position<'a>,_limits:&'a Limits)->BoxFuture<'a,Option<SearchResult>>{
+		Box::pin(async move{
+			let moves=position.board.legal_move_list(position.is_black,position.rules);
+			let (pos,flips)=moves.into_iter().map(|pos|{
+				let mut board=position.board.clone();
+				let (_,flips)=board.put_stone_silent(pos,position.is_black,position.rules);
+				let flips=flips as i32;
+				(pos,if position.rules.is_llotheo{
+					-flips
+				}else{
+					flips
+				})
+			}).max_by_key(|(_,flips)|*flips)?;
+			let mut res=SearchResult::new(pos,"greedy");
+			res.eval=Some(flips);
+			Some(res)
+		})
+	}
+}
+pub struct RandomEngine;
+impl Engine for RandomEngine{
+	fn name(&self)->String{
+		"random".to_owned()
+	}
+	fn search<'a>(&'a self,position:&'a Position<'a>,_limits:&'a Limits)->BoxFuture<'a,Option<SearchResult>>{
+		Box::pin(async move{
+			let moves=position.board.legal_move_list(position.is_black,position.rules);
+			if moves.is_empty(){
+				return None;
+			}
+			let pos=moves[rand::thread_rng().gen_range(0..moves.len())];
+			Some(SearchResult::new(pos,"random"))
+		})
+	}
+}
+/**先頭から順に試す*/
+pub struct Chain(pub Vec<Box<dyn Engine>>);
+impl Engine for Chain{
+	fn name(&self)->String{
+		self.0.iter().map(|e|e.name()).collect::<Vec<_>>().join(" > ")
+	}
+	fn search<'a>(&'a self,position:&'a Position<'a>,limits:&'a Limits)->BoxFuture<'a,Option<SearchResult>>{
+		Box::pin(async move{
+			let legal_moves=position.board.legal_move_list(position.is_black,position.rules);
+			for engine in self.0.iter(){
+				if let Some(res)=engine.search(position,limits).await{
+					//置けない手を返したら次のエンジンに任せる
+					if legal_moves.contains(&res.pos){
+						return Some(res);
+					}
+					tracing::warn!(engine=%res.engine,pos=res.pos,"engine returned illegal move");
+				}
+			}
+			None
+		})
+	}
+	fn cancel(&self,game_id:&str){
+		for engine in self.0.iter(){
+			engine.cancel(game_id);
+		}
+	}
+}
+/**設定のengineの名前の順に組み立てる 未指定なら定石 → dekunobou(httpかffi) → local*/
+pub fn build(config:&crate::ConfigFile,client:&Client,book:Option<book::OpeningBook>)->Chain{
+	let default_names=||{
+		let mut names=vec!["book".to_owned()];
+		names.push(if config.dekunobou.is_some(){
+			"http".to_owned()
+		}else{
+			"ffi".to_owned()
+		});
+		names.push("local".to_owned());
+		names
+	};
+	let names=config.engine.clone().unwrap_or_else(default_names);
+	let mut book=book;
+	let mut engines:Vec<Box<dyn Engine>>=vec![];
+	for name in names.iter(){
+		match name.as_str(){
+			"book"=>{
+				if let Some(book)=book.take(){
+					engines.push(Box::new(BookEngine(book)));
+				}
+			},
+			"ffi"=>{
+				let threads=config.engine_threads.unwrap_or_else(||std::thread::available_parallelism().map(|n|n.get()).unwrap_or(1));
+				engines.push(Box::new(FfiEngine(pool::EnginePool::new(threads))));
+			},
+			"http"=>match config.dekunobou.as_ref(){
+				Some(url)=>engines.push(Box::new(crate::http_engine::HttpEngine::new(client.clone(),url.clone(),config.http_engine.clone()))),
+				None=>tracing::warn!("engine http needs dekunobou url"),
+			},
+			"external"=>match config.external_engine.as_ref(){
+				Some(external)=>engines.push(Box::new(crate::external::ExternalEngine::new(external.clone()))),
+				None=>tracing::warn!("engine external needs external_engine"),
+			},
+			"local"=>engines.push(Box::new(LocalEngine)),
+			"greedy"=>engines.push(Box::new(GreedyEngine)),
+			"random"=>engines.push(Box::new(RandomEngine)),
+			name=>tracing::warn!("unknown engine {}",name),
+		}
+	}
+	let chain=Chain(engines);
+	tracing::info!(engine=%chain.name(),"engines ready");
+	chain
+}
@@ -0,0 +1,168 @@
+//! 標準入出力で行単位のやり取りをする外部エンジン
+//!
+//! 送る内容
+//! ```text
+//! position <幅> <高さ> <マス(-空 b黒 w白 #置けない)> <手番 b|w>
+//! rules <isLlotheo 0|1> <canPutEverywhere 0|1> <loopedBoard 0|1>
+//! go depth <読みの深さ> perfect <完全読みの深さ> [movetime <ミリ秒>]
+//! ```
+//! 返す内容は`move <位置(0から)>` 打つ手がなければ`move none`
+//! その前に`info eval <評価値> pv <位置>...`を返してもよい
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use crate::engine::{Engine, Limits, Position, SearchResult};
+use crate::WALL;
+
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct ExternalEngineConfig{
+	pub command:String,
+	#[serde(default)]
+	pub args:Vec<String>,
+	/**movetimeがない時に返事を待つ時間(秒)*/
+	#[serde(default="default_timeout_secs")]
+	pub timeout_secs:u64,
+}
+fn default_timeout_secs()->u64{
+	60
+}
+struct Process{
+	/**dropすると終了させる*/
+	_child:tokio::process::Child,
+	stdin:tokio::process::ChildStdin,
+	stdout:tokio::io::Lines<tokio::io::BufReader<tokio::process::ChildStdout>>,
+}
+pub struct ExternalEngine{
+	config:ExternalEngineConfig,
+	/**落ちたりタイムアウトしたらNoneにして次の探索で起動し直す*/
+	process:Mutex<Option<Process>>,
+}
+impl ExternalEngine{
+	pub fn new(config:ExternalEngineConfig)->Self{
+		Self{
+			config,
+			process:Mutex::new(None),
+		}
+	}
+	fn spawn(&self)->std::io::Result<Process>{
+		tracing::info!(command=%self.config.command,args=?self.config.args,"start engine");
+		let mut child=tokio::process::Command::new(&self.config.command)
+			.args(&self.config.args)
+			.stdin(std::process::Stdio::piped())
+			.stdout(std::process::Stdio::piped())
+			.kill_on_drop(true)
+			.spawn()?;
+		let stdin=child.stdin.take().ok_or_else(||std::io::Error::other("no stdin"))?;
+		let stdout=child.stdout.take().ok_or_else(||std::io::Error::other("no stdout"))?;
+		Ok(Process{
+			_child:child,
+			stdin,
+			stdout:tokio::io::BufReader::new(stdout).lines(),
+		})
+	}
+	/**1局面分のやり取り*/
+	async fn exchange(process:&mut Process,request:&str)->Result<Option<SearchResult>,String>{
+		process.stdin.write_all(request.as_bytes()).await.map_err(|e|format!("write {:?}",e))?;
+		process.stdin.flush().await.map_err(|e|format!("write {:?}",e))?;
+		let mut res=SearchResult{
+			pos:0,
+			eval:None,
+			pv:vec![],
+			engine:"external".to_owned(),
+		};
+		loop{
+			let line=match process.stdout.next_line().await{
+				Ok(Some(line))=>line,
+				Ok(None)=>return Err("engine exited".to_owned()),
+				Err(e)=>return Err(format!("read {:?}",e)),
+			};
+			let mut words=line.split_whitespace();
+			match words.next(){
+				Some("move")=>{
+					return match words.next(){
+						Some("none")=>Ok(None),
+						Some(pos)=>match pos.parse::<u16>(){
+							Ok(pos)=>{
+								res.pos=pos;
+								Ok(Some(res))
+							},
+							Err(_)=>Err(format!("invalid move {}",line)),
+						},
+						None=>Err(format!("invalid move {}",line)),
+					};
+				},
+				Some("info")=>{
+					while let Some(key)=words.next(){
+						match key{
+							"eval"=>res.eval=words.next().and_then(|v|v.parse().ok()),
+							"pv"=>res.pv=words.by_ref().filter_map(|v|v.parse().ok()).collect(),
+							_=>{},
+						}
+					}
+				},
+				_=>tracing::debug!("engine: {}",line),
+			}
+		}
+	}
+}
+/**positionとrulesとgoの3行*/
+fn request(position:&Position,limits:&Limits)->String{
+	let board=position.board;
+	let cells=board.cells().map(|c|match c{
+		1=>'b',
+		2=>'w',
+		WALL=>'#',
+		_=>'-',
+	}).collect::<String>();
+	let flag=|b:bool|if b{
+		1
+	}else{
+		0
+	};
+	let mut s=format!("position {} {} {} {}\n",board.width(),board.height(),cells,if position.is_black{
+		'b'
+	}else{
+		'w'
+	});
+	s.push_str(&format!("rules {} {} {}\n",flag(position.rules.is_llotheo),flag(position.rules.can_put_everywhere),flag(position.rules.looped_board)));
+	s.push_str(&format!("go depth {} perfect {}",limits.depth,limits.perfect_search_depth));
+	if let Some(millis)=limits.time_millis{
+		s.push_str(&format!(" movetime {}",millis));
+	}
+	s.push('\n');
+	s
+}
+impl Engine for ExternalEngine{
+	fn name(&self)->String{
+		format!("external {}",self.config.command)
+	}
+	fn search<'a>(&'a self,position:&'a Position<'a>,limits:&'a Limits)->BoxFuture<'a,Option<SearchResult>>{
+		Box::pin(async move{
+			//1つのプロセスを対局で順番に使う
+			let mut process=self.process.lock().await;
+			if process.is_none(){
+				*process=self.spawn().map_err(|e|tracing::error!(command=%self.config.command,"engine start error {:?}",e)).ok();
+			}
+			let p=process.as_mut()?;
+			let timeout=limits.time_millis.unwrap_or(self.config.timeout_secs*1000);
+			let res=tokio::time::timeout(tokio::time::Duration::from_millis(timeout),Self::exchange(p,&request(position,limits))).await;
+			match res{
+				Ok(Ok(res))=>res,
+				Ok(Err(e))=>{
+					//次の探索で起動し直す
+					tracing::warn!(command=%self.config.command,"engine error {}",e);
+					*process=None;
+					None
+				},
+				Err(_)=>{
+					//遅れて返ってきた手を次の局面の答えと取り違えないよう止める
+					tracing::warn!(command=%self.config.command,timeout_millis=timeout,"engine timeout");
+					*process=None;
+					None
+				},
+			}
+		})
+	}
+}
@@ -0,0 +1,166 @@
+//! dekunobouのHTTPサーバーに手を聞く
+use futures_util::future::BoxFuture;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::engine::{Engine, Limits, Position, SearchResult};
+use crate::DekunobouBoard;
+
+#[derive(Clone,Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct HttpEngineConfig{
+	/**持ち時間の指定がない時の1回の問い合わせの上限(秒)*/
+	pub timeout_secs:u64,
+	/**失敗した時に問い合わせ直す回数*/
+	pub retries:u32,
+	/**最初の問い合わせ直しまでの間隔(ミリ秒) 1回ごとに倍にする*/
+	pub backoff_millis:u64,
+}
+impl Default for HttpEngineConfig{
+	fn default()->Self{
+		Self{
+			timeout_secs:30,
+			retries:3,
+			backoff_millis:200,
+		}
+	}
+}
+#[derive(Serialize,Deserialize,Debug)]
+struct DekunobouRequest{
+	board:DekunobouBoard,
+	depth:u8,
+	perfect_search_depth:u8,
+	/**黒を置かせる時は0/白を置かせる時は1*/
+	turn:u8,
+}
+#[derive(Serialize,Deserialize,Debug)]
+struct DekunobouResponse{
+	#[serde(rename = "move")]
+	pos:String,
+}
+#[derive(Debug)]
+pub enum HttpEngineError{
+	/**接続できないかタイムアウト*/
+	Transport(reqwest::Error),
+	Status(reqwest::StatusCode),
+	/**返ってきた内容が読めない*/
+	Decode(String),
+	/**盤の外や置けない所*/
+	IllegalMove(u16),
+	/**8x8以外の盤など問い合わせられない局面*/
+	Unsupported,
+}
+impl std::fmt::Display for HttpEngineError{
+	fn fmt(&self,f:&mut std::fmt::Formatter<'_>)->std::fmt::Result{
+		match self{
+			HttpEngineError::Transport(e)=>write!(f,"transport error {}",e),
+			HttpEngineError::Status(status)=>write!(f,"status {}",status),
+			HttpEngineError::Decode(s)=>write!(f,"decode error {}",s),
+			HttpEngineError::IllegalMove(pos)=>write!(f,"illegal move {}",pos),
+			HttpEngineError::Unsupported=>write!(f,"unsupported position"),
+		}
+	}
+}
+impl HttpEngineError{
+	/**問い合わせ直せば直るかもしれないか*/
+	fn is_retryable(&self)->bool{
+		match self{
+			HttpEngineError::Transport(_)=>true,
+			HttpEngineError::Status(status)=>status.is_server_error()||*status==reqwest::StatusCode::TOO_MANY_REQUESTS,
+			_=>false,
+		}
+	}
+}
+pub struct HttpEngine{
+	client:Client,
+	url:String,
+	config:HttpEngineConfig,
+}
+impl HttpEngine{
+	pub fn new(client:Client,url:String,config:HttpEngineConfig)->Self{
+		Self{
+			client,
+			url,
+			config,
+		}
+	}
+	async fn request(&self,req:&DekunobouRequest,timeout:std::time::Duration)->Result<u16,HttpEngineError>{
+		let res=self.client.put(self.url.as_str())
+			.header("Content-Type","application/json")
+			.body(serde_json::to_string(req).unwrap())
+			.timeout(timeout)
+			.send().await.map_err(HttpEngineError::Transport)?;
+		if !res.status().is_success(){
+			return Err(HttpEngineError::Status(res.status()));
+		}
+		let b=res.bytes().await.map_err(HttpEngineError::Transport)?;
+		let v=serde_json::from_slice::<DekunobouResponse>(&b).map_err(|e|HttpEngineError::Decode(format!("{:?} {:?}",e,String::from_utf8_lossy(&b))))?;
+		//dekunobou return int 32bit
+		let pos=v.pos.trim().parse::<u16>().map_err(|e|HttpEngineError::Decode(format!("{:?} {:?}",e,v.pos)))?;
+		if pos>=64{
+			return Err(HttpEngineError::IllegalMove(pos));
+		}
+		Ok(pos)
+	}
+	/**失敗したら間隔を倍にしながら持ち時間の内で問い合わせ直す*/
+	pub async fn best_move(&self,position:&Position<'_>,limits:&Limits)->Result<u16,HttpEngineError>{
+		if !position.rules.is_standard(){
+			return Err(HttpEngineError::Unsupported);
+		}
+		let board=position.board.to_dekunobou().ok_or(HttpEngineError::Unsupported)?;
+		let req=DekunobouRequest{
+			board,
+			depth:limits.depth.min(u8::MAX as u32) as u8,
+			perfect_search_depth:limits.perfect_search_depth.min(u8::MAX as u32) as u8,
+			turn:if position.is_black{
+				0
+			}else{
+				1
+			},
+		};
+		//問い合わせ直しと待ちを合わせて持ち時間を超えないようにする
+		let deadline=tokio::time::Instant::now()+std::time::Duration::from_millis(limits.time_millis.unwrap_or(self.config.timeout_secs*1000));
+		let mut backoff=std::time::Duration::from_millis(self.config.backoff_millis);
+		let mut attempt=0;
+		loop{
+			let timeout=deadline.saturating_duration_since(tokio::time::Instant::now());
+			let res=self.request(&req,timeout).await.and_then(|pos|{
+				if position.board.legal_move_list(position.is_black,position.rules).contains(&pos){
+					Ok(pos)
+				}else{
+					Err(HttpEngineError::IllegalMove(pos))
+				}
+			});
+			match res{
+				Err(e) if e.is_retryable()&&attempt<self.config.retries&&deadline>tokio::time::Instant::now()+backoff=>{
+					tracing::warn!(attempt=attempt+1,backoff=?backoff,"dekunobou {}, retrying",e);
+					tokio::time::sleep(backoff).await;
+					backoff*=2;
+					attempt+=1;
+				},
+				res=>return res,
+			}
+		}
+	}
+}
+impl Engine for HttpEngine{
+	fn name(&self)->String{
+		format!("http {}",self.url)
+	}
+	fn search<'a>(&'a self,position:&'a Position<'a>,limits:&'a Limits)->BoxFuture<'a,Option<SearchResult>>{
+		Box::pin(async move{
+			match self.best_move(position,limits).await{
+				Ok(pos)=>Some(SearchResult::new(pos,"http")),
+				Err(HttpEngineError::Unsupported)=>{
+					//次のエンジンに任せる
+					tracing::debug!("dekunobou skipped unsupported position");
+					None
+				},
+				Err(e)=>{
+					tracing::warn!("dekunobou {}",e);
+					None
+				}
+			}
+		})
+	}
+}
@@ -0,0 +1,231 @@
+//! 終局した対局の記録をGGFと"f5d6c3..."形式の棋譜で保存する
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{GameRules, MiBoard, WALL};
+
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct KifuMove{
+	pub pos:u16,
+	pub is_black:bool,
+	/**unix時間(ミリ秒)*/
+	pub time:u64,
+}
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct GameResult{
+	pub black:u32,
+	pub white:u32,
+	/**引き分けならNone*/
+	pub winner_id:Option<String>,
+	/**"normal","surrender","timeout"など*/
+	pub reason:String,
+}
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct GameRecord{
+	pub game_id:String,
+	pub url:String,
+	pub self_id:String,
+	pub opponent_id:String,
+	/**@username@host*/
+	pub opponent:Option<String>,
+	pub self_is_black:bool,
+	pub rules:GameRules,
+	pub initial_board:MiBoard,
+	pub moves:Vec<KifuMove>,
+	pub engine:String,
+	pub depth:u32,
+	pub perfect_search_depth:u32,
+	/**unix時間(ミリ秒)*/
+	pub started_at:u64,
+	pub ended_at:u64,
+	pub result:GameResult,
+}
+pub fn now_millis()->u64{
+	std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d|d.as_millis() as u64).unwrap_or(0)
+}
+/**"f5"のような座標表記 列はa,b,c...で行は1から*/
+fn square_name(board:&MiBoard,pos:u16)->String{
+	let col=pos as usize%board.width();
+	let row=pos as usize/board.width();
+	let col=if col<26{
+		((b'a'+col as u8) as char).to_string()
+	}else{
+		format!("({})",col)
+	};
+	format!("{}{}",col,row+1)
+}
+/**unix時間(ミリ秒)をGGFの"2024.01.02_03:04:05.GMT"にする*/
+fn ggf_date(millis:u64)->String{
+	let secs=millis/1000;
+	let days=(secs/86400) as i64;
+	let rem=secs%86400;
+	//days from civil(Howard Hinnant)の逆変換
+	let z=days+719468;
+	let era=z.div_euclid(146097);
+	let doe=z-era*146097;
+	let yoe=(doe-doe/1460+doe/36524-doe/146096)/365;
+	let doy=doe-(365*yoe+yoe/4-yoe/100);
+	let mp=(5*doy+2)/153;
+	let day=doy-(153*mp+2)/5+1;
+	let month=if mp<10{
+		mp+3
+	}else{
+		mp-9
+	};
+	let year=yoe+era*400+if month<=2{
+		1
+	}else{
+		0
+	};
+	format!("{:04}.{:02}.{:02}_{:02}:{:02}:{:02}.GMT",year,month,day,rem/3600,rem%3600/60,rem%60)
+}
+/**"f5d6c3..."形式の棋譜 パスは書かない*/
+pub fn transcript(record:&GameRecord)->String{
+	let mut s=String::new();
+	for m in record.moves.iter(){
+		s.push_str(&square_name(&record.initial_board,m.pos));
+	}
+	s
+}
+/**GGF形式の棋譜 置けないマスのあるマップや正方形でない盤はGGFで表せないのでNone*/
+pub fn ggf(record:&GameRecord)->Option<String>{
+	let board=&record.initial_board;
+	if board.width()!=board.height()||board.cells().any(|c|c==WALL){
+		return None;
+	}
+	let name=|is_self:bool|->String{
+		if is_self{
+			record.self_id.clone()
+		}else{
+			record.opponent.clone().unwrap_or(record.opponent_id.clone())
+		}
+	};
+	let mut s=String::new();
+	s.push_str("(;GM[Othello]");
+	s.push_str(&format!("PC[{}]",record.url));
+	s.push_str(&format!("DT[{}]",ggf_date(record.started_at)));
+	s.push_str(&format!("PB[{}]",name(record.self_is_black)));
+	s.push_str(&format!("PW[{}]",name(!record.self_is_black)));
+	let diff=record.result.black as i32-record.result.white as i32;
+	let suffix=match record.result.reason.as_str(){
+		"surrender"=>":r",
+		"timeout"=>":t",
+		_=>"",
+	};
+	s.push_str(&format!("RE[{:+}.000{}]",diff,suffix));
+	s.push_str(&format!("TI[{}]",(record.ended_at.saturating_sub(record.started_at))/1000));
+	s.push_str(&format!("TY[{}]",board.width()));
+	let mut bo=format!("{} ",board.width());
+	for (i,c) in board.cells().enumerate(){
+		bo.push(match c{
+			1=>'*',
+			2=>'O',
+			_=>'-',
+		});
+		if i%board.width()==board.width()-1{
+			bo.push(' ');
+		}
+	}
+	//黒から打つ
+	bo.push('*');
+	s.push_str(&format!("BO[{}]",bo));
+	let mut next_is_black=true;
+	for m in record.moves.iter(){
+		if m.is_black!=next_is_black{
+			//相手が置けなかったのでパスを挟む
+			s.push_str(if next_is_black{
+				"B[PA]"
+			}else{
+				"W[PA]"
+			});
+		}
+		s.push_str(&format!("{}[{}]",if m.is_black{
+			"B"
+		}else{
+			"W"
+		},square_name(board,m.pos).to_uppercase()));
+		next_is_black = !m.is_black;
+	}
+	s.push_str(";)");
+	Some(s)
+}
+/**dirにid.json(対局情報) id.ggf id.txt(棋譜)を書き出す*/
+pub fn save(dir:&str,record:&GameRecord)->std::io::Result<()>{
+	std::fs::create_dir_all(dir)?;
+	let base=std::path::Path::new(dir);
+	let json=serde_json::to_string_pretty(record).map_err(std::io::Error::other)?;
+	std::fs::write(base.join(format!("{}.json",record.game_id)),json)?;
+	if let Some(ggf)=ggf(record){
+		let mut f=std::fs::File::create(base.join(format!("{}.ggf",record.game_id)))?;
+		writeln!(f,"{}",ggf)?;
+	}
+	let mut f=std::fs::File::create(base.join(format!("{}.txt",record.game_id)))?;
+	writeln!(f,"{}",transcript(record))?;
+	Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests{
+	use super::*;
+
+	/**botが黒で勝った対局の記録*/
+	pub(crate) fn record(moves:&[(u16,bool)])->GameRecord{
+		GameRecord{
+			game_id:"game".to_owned(),
+			url:"https://example.com/reversi/g/game".to_owned(),
+			self_id:"bot".to_owned(),
+			opponent_id:"user".to_owned(),
+			opponent:Some("@user@example.com".to_owned()),
+			self_is_black:true,
+			rules:GameRules::default(),
+			initial_board:MiBoard::new(),
+			moves:moves.iter().map(|(pos,is_black)|KifuMove{
+				pos:*pos,
+				is_black:*is_black,
+				time:0,
+			}).collect(),
+			engine:"local".to_owned(),
+			depth:0,
+			perfect_search_depth:0,
+			started_at:1704164645000,
+			ended_at:1704164705000,
+			result:GameResult{
+				black:40,
+				white:24,
+				winner_id:Some("bot".to_owned()),
+				reason:"surrender".to_owned(),
+			},
+		}
+	}
+	#[test]
+	fn ggf_dates(){
+		assert_eq!(ggf_date(0),"1970.01.01_00:00:00.GMT");
+		assert_eq!(ggf_date(951782400000),"2000.02.29_00:00:00.GMT");
+		assert_eq!(ggf_date(1704164645999),"2024.01.02_03:04:05.GMT");
+		assert_eq!(ggf_date(1740787199000),"2025.02.28_23:59:59.GMT");
+	}
+	#[test]
+	fn transcript_uses_square_names(){
+		assert_eq!(transcript(&record(&[(37,true),(43,false),(18,true)])),"f5d6c3");
+		//大きいマップでも列は英字で行は1から
+		let mut r=record(&[(25,true)]);
+		r.initial_board=MiBoard::from_map(&vec!["-".repeat(12);12]).unwrap();
+		assert_eq!(transcript(&r),"b3");
+	}
+	#[test]
+	fn ggf_writes_moves_and_passes(){
+		let ggf=ggf(&record(&[(37,true),(43,true)])).unwrap();
+		assert!(ggf.starts_with("(;GM[Othello]PC[https://example.com/reversi/g/game]DT[2024.01.02_03:04:05.GMT]PB[bot]PW[@user@example.com]"));
+		assert!(ggf.contains("RE[+16.000:r]TI[60]TY[8]"));
+		assert!(ggf.contains("BO[8 -------- -------- -------- ---O*--- ---*O--- -------- -------- -------- *]"));
+		//続けて黒が打ったので白のパスを挟む
+		assert!(ggf.ends_with("B[F5]W[PA]B[D6];)"));
+	}
+	#[test]
+	fn ggf_rejects_blocked_cells(){
+		let mut r=record(&[]);
+		r.initial_board=MiBoard::from_map(&["-- ".to_owned(),"---".to_owned(),"---".to_owned()]).unwrap();
+		assert!(ggf(&r).is_none());
+	}
+}
@@ -0,0 +1,45 @@
+//! ログの出力
+//!
+//! 対局ごとにgame(id,opponent) 1手ごとにmove(ply) websocketの接続ごとにconnection(host)のspanを付ける
+//! 標準出力はオペレーターのコマンドの応答に使うのでログは標準エラーに書く
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Clone,Copy,Default,Serialize,Deserialize,Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat{
+	/**人が読む1行形式*/
+	#[default]
+	Human,
+	/**1行に1つのJSON*/
+	Json,
+}
+#[derive(Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct LogConfig{
+	pub format:LogFormat,
+	/**"info"や"dekunobou_bot=debug"のようなフィルタ 環境変数RUST_LOGがあればそちらを使う*/
+	pub level:String,
+}
+impl Default for LogConfig{
+	fn default()->Self{
+		Self{
+			format:LogFormat::Human,
+			level:"info".to_owned(),
+		}
+	}
+}
+pub fn init(config:&LogConfig){
+	let (filter,invalid)=match EnvFilter::try_from_default_env().or_else(|_|EnvFilter::try_new(&config.level)){
+		Ok(filter)=>(filter,None),
+		Err(e)=>(EnvFilter::new("info"),Some(e)),
+	};
+	let builder=tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+	match config.format{
+		LogFormat::Human=>builder.init(),
+		LogFormat::Json=>builder.json().with_current_span(true).with_span_list(true).init(),
+	}
+	if let Some(e)=invalid{
+		tracing::warn!(level=%config.level,"invalid log level {}",e);
+	}
+}
@@ -1,12 +1,31 @@
 use core::str;
 use std::{collections::HashMap, sync::{atomic::{AtomicBool, AtomicU32}, Arc}};
 
-use futures_util::{future::BoxFuture, stream::{SplitSink, SplitStream}, SinkExt, StreamExt, TryStreamExt};
+use futures_util::{future::BoxFuture, stream::{SplitSink, SplitStream}, FutureExt, SinkExt, StreamExt, TryStreamExt};
 use rand::SeedableRng;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
-use dekunobou;
+use tracing::Instrument;
+use engine::Engine;
+
+mod bitboard;
+mod book;
+mod clock;
+mod command;
+mod engine;
+mod external;
+mod http_engine;
+mod kifu;
+mod logging;
+mod note;
+mod policy;
+mod pool;
+mod random_match;
+mod search;
+mod sparring;
+mod stats;
+mod strength;
 
 #[derive(Serialize,Deserialize,Debug)]
 struct WSResult{
@@ -32,8 +51,41 @@ struct ConfigFile{
 	dekunobou:Option<String>,
 	depth:u32,
 	perfect_search_depth:u32,
+	/**1手あたりの思考時間の上限(秒) 対局の持ち時間が短ければそちらを使い 超えたら手元の浅い探索の手を打つ*/
+	move_time_limit:Option<u64>,
+	/**棋譜の保存先 未指定ならrecords*/
+	record_dir:Option<String>,
+	/**対局相手ごとの成績の保存先 未指定なら棋譜の保存先のstats.json*/
+	stats_file:Option<String>,
+	book:Option<book::BookConfig>,
+	/**手を選ぶエンジンを試す順 book,ffi,http,external,local,greedy,random*/
+	engine:Option<Vec<String>>,
+	/**dekunobouのHTTPサーバーへの問い合わせ方*/
+	#[serde(default)]
+	http_engine:http_engine::HttpEngineConfig,
+	/**標準入出力でやり取りするエンジン*/
+	external_engine:Option<external::ExternalEngineConfig>,
+	/**FFIで探索するスレッドの数 未指定ならCPUの数*/
+	engine_threads:Option<usize>,
+	/**相手ごとの強さ*/
+	#[serde(default)]
+	strength:strength::StrengthConfig,
+	/**招待を受ける条件*/
+	#[serde(default)]
+	policy:policy::InvitePolicy,
+	/**定期的にこちらから申し込む対局*/
+	sparring:Option<sparring::SparringConfig>,
+	/**ランダムマッチに並ぶ*/
+	random_match:Option<random_match::RandomMatchConfig>,
+	/**終局したら結果をノートに投稿する*/
+	note:Option<note::NoteConfig>,
+	/**メンションでコマンドを受け付ける*/
+	command:Option<command::CommandConfig>,
+	/**ログの形式と出力するレベル*/
+	#[serde(default)]
+	log:logging::LogConfig,
 }
-#[derive(Serialize,Deserialize,Debug)]
+#[derive(Clone,Serialize,Deserialize,Debug)]
 struct MiUser{
 	id:String,
 	name:Option<String>,
@@ -44,6 +96,15 @@ struct MiUser{
 	#[serde(rename = "isCat")]
 	is_cat:bool,
 }
+impl MiUser{
+	/**ホスト名 ローカルのユーザーならインスタンスのホスト名*/
+	fn host_or_instance(&self,instance:&str)->Option<String>{
+		match self.host.as_ref(){
+			Some(host)=>Some(host.clone()),
+			None=>reqwest::Url::parse(instance).ok().and_then(|url|url.host_str().map(|h|h.to_owned())),
+		}
+	}
+}
 #[derive(Serialize,Deserialize,Debug)]
 struct MatchRequest{
 	accept_only:bool,
@@ -52,12 +113,118 @@ struct MatchRequest{
 	user_id:String,
 }
 #[derive(Serialize,Deserialize,Debug)]
+struct SurrenderRequest{
+	i:String,
+	#[serde(rename = "gameId")]
+	game_id:String,
+}
+#[derive(Serialize,Deserialize,Debug)]
+struct ShowGameRequest{
+	i:String,
+	#[serde(rename = "gameId")]
+	game_id:String,
+}
+/**reversi/show-gameやstartedで送られてくる対局の状態*/
+#[derive(Serialize,Deserialize,Debug)]
+struct ReversiGameDetail{
+	id:String,
+	#[serde(rename = "user1Id")]
+	user1_id:String,
+	#[serde(rename = "user2Id")]
+	user2_id:String,
+	/**1ならuser1が黒 2ならuser2が黒*/
+	black:Option<u8>,
+	#[serde(default)]
+	map:Vec<String>,
+	/**[前の手からの経過時間,黒なら1,操作,位置]*/
+	#[serde(default)]
+	logs:Vec<Vec<i64>>,
+	#[serde(rename = "isStarted",default)]
+	is_started:bool,
+	/**1手の持ち時間(秒)*/
+	#[serde(rename = "timeLimitForEachTurn",default)]
+	time_limit_for_each_turn:Option<u64>,
+	/**投了したユーザー*/
+	#[serde(rename = "surrenderedUserId",default)]
+	surrendered_user_id:Option<String>,
+	/**時間切れになったユーザー*/
+	#[serde(rename = "timeoutUserId",default)]
+	timeout_user_id:Option<String>,
+	#[serde(flatten)]
+	rules:GameRules,
+}
+#[derive(Serialize,Deserialize,Debug)]
+struct ShowUserRequest{
+	i:String,
+}
+#[derive(Serialize,Deserialize,Debug)]
 struct MatchResponse{
 	id:String,
 	#[serde(rename = "user1Id")]
 	user1_id:String,
 	#[serde(rename = "user2Id")]
 	user2_id:String,
+	#[serde(default)]
+	user1:Option<MiUser>,
+	#[serde(default)]
+	user2:Option<MiUser>,
+}
+/**次に打つ側 サーバーと同じく置ける場所がなければパスして相手の番*/
+#[derive(Clone,Copy,PartialEq,Serialize,Deserialize,Debug)]
+enum Turn{
+	Black,
+	White,
+	/**どちらも置けないので終局*/
+	Over,
+}
+impl Turn{
+	fn of(is_black:bool)->Self{
+		if is_black{
+			Turn::Black
+		}else{
+			Turn::White
+		}
+	}
+}
+/**送った手がlogで確認できるまで待つ時間(ミリ秒)*/
+const ACK_TIMEOUT_MILLIS:u64=3000;
+/**同じ手を送る回数の上限 超えたらサーバーの状態を取り直す*/
+const MAX_SEND_ATTEMPTS:u32=3;
+/**こちらからの申し込みをmatchedと突き合わせる期間*/
+const INVITE_TTL:std::time::Duration=std::time::Duration::from_secs(600);
+/**投了のAPIを呼ぶ回数の上限*/
+const SURRENDER_ATTEMPTS:u32=3;
+/**送ったがサーバーのlogで確認できていない手*/
+#[derive(Clone,Serialize,Deserialize,Debug)]
+struct PendingMove{
+	//サーバーがidで重複を弾くとは限らない 受理された後の送り直しは手番でないので無視されるはずだが
+	//logが2回届いても手元で読み飛ばせるようにacked_idを覚えておく
+	/**putStoneのid 送り直す時も同じものを使う*/
+	id:String,
+	pos:u16,
+	attempts:u32,
+	/**最後に送った時刻 unix時間(ミリ秒)*/
+	sent_at:u64,
+}
+/**別のタスクで読んだ探索の結果*/
+struct SearchDone{
+	/**読み始めた時の手数 盤面が変わっていたら捨てる*/
+	ply:usize,
+	res:Option<engine::SearchResult>,
+	elapsed:u64,
+	timed_out:bool,
+}
+type SearchTask=tokio::task::JoinHandle<SearchDone>;
+/**送った手のlogが待ち時間内に届かなかった時にすること*/
+#[derive(PartialEq,Debug)]
+enum AckTimeout{
+	/**同じidで送り直す*/
+	Resend,
+	/**送り尽くしたのでサーバーの状態を取り直し index手目にposがあるか確かめる*/
+	Resync{
+		pos:u16,
+		index:usize,
+	},
 }
 #[derive(Serialize,Deserialize,Debug)]
 struct GameContext{
@@ -66,11 +233,108 @@ struct GameContext{
 	user2_id:String,
 	user2_is_self:bool,
 	user2_is_black:bool,
-	user2_is_active_player:bool,
-	board:DekunobouBoard,
-	log:Vec<u8>,
+	/**サーバーのlogから決めた手番*/
+	turn:Turn,
+	board:MiBoard,
+	log:Vec<kifu::KifuMove>,
+	/**サーバーのlogで確認が取れた手数*/
+	synced:usize,
+	/**確認待ちの自分の手*/
+	pending:Option<PendingMove>,
+	/**最後に確認が取れた自分の手のid 送り直しで2回届いたlogを読み飛ばす*/
+	acked_id:Option<String>,
+	/**投了済みかどうか*/
+	surrendered:bool,
+	rules:GameRules,
+	opponent:Option<MiUser>,
+	/**棋譜用の開始局面*/
+	initial_board:MiBoard,
+	/**unix時間(ミリ秒)*/
+	started_at:Option<u64>,
+	/**棋譜を保存済みかどうか*/
+	recorded:bool,
+	/**この対局で使う強さ*/
+	profile:strength::StrengthProfile,
+	/**対局を頼まれたノートのid 結果の返信先*/
+	reply_to:Option<String>,
+	/**持ち時間と探索時間*/
+	clock:clock::TimeManager,
+	/**持ち時間から決めたこの手の読みの深さ*/
+	search_profile:strength::StrengthProfile,
+	/**手を返したエンジンの名前*/
+	engines_used:Vec<String>,
+}
+/**Misskeyリバーシの変則ルール*/
+#[derive(Clone,Copy,Default,Serialize,Deserialize,Debug)]
+#[serde(default)]
+struct GameRules{
+	/**石が少ない方が勝ち*/
+	#[serde(rename = "isLlotheo")]
+	is_llotheo:bool,
+	/**挟めなくてもどこにでも置ける*/
+	#[serde(rename = "canPutEverywhere")]
+	can_put_everywhere:bool,
+	/**盤の端が反対側とつながっている*/
+	#[serde(rename = "loopedBoard")]
+	looped_board:bool,
+}
+impl GameRules{
+	/**dekunobouで探索できる通常ルールかどうか*/
+	fn is_standard(&self)->bool{
+		!self.is_llotheo&&!self.can_put_everywhere&&!self.looped_board
+	}
+	/**updateSettingsのkey/valueを反映する 対応していないkeyならfalse*/
+	fn update(&mut self,key:&str,value:&serde_json::Value)->bool{
+		let flag=match key{
+			"isLlotheo"=>&mut self.is_llotheo,
+			"canPutEverywhere"=>&mut self.can_put_everywhere,
+			"loopedBoard"=>&mut self.looped_board,
+			_=>return false,
+		};
+		match value.as_bool(){
+			Some(v)=>{
+				*flag=v;
+				true
+			},
+			None=>false,
+		}
+	}
 }
 impl GameContext{
+	/**マッチした対局の情報から作る opponentがNoneなら対局情報に含まれるユーザーを使う*/
+	fn from_match(res:MatchResponse,self_id:&str,opponent:Option<MiUser>,config:&ConfigFile)->Self{
+		let user2_is_self=res.user2_id==self_id;
+		let opponent=opponent.or(if user2_is_self{
+			res.user1
+		}else{
+			res.user2
+		});
+		let profile=strength::select(config,opponent.as_ref());
+		Self{
+			id:res.id,
+			user2_is_self,
+			user2_is_black:false,
+			turn:Turn::Black,
+			user1_id:res.user1_id,
+			user2_id:res.user2_id,
+			board:MiBoard::new(),
+			log:vec![],
+			synced:0,
+			pending:None,
+			acked_id:None,
+			surrendered:false,
+			rules:GameRules::default(),
+			opponent,
+			initial_board:MiBoard::new(),
+			started_at:None,
+			recorded:false,
+			profile:profile.clone(),
+			reply_to:None,
+			clock:clock::TimeManager::default(),
+			search_profile:profile.clone(),
+			engines_used:vec![],
+		}
+	}
 	fn self_id(&self)->&str{
 		if self.user2_is_self{
 			self.user2_id.as_str()
@@ -78,155 +342,833 @@ impl GameContext{
 			self.user1_id.as_str()
 		}
 	}
+	fn opponent_id(&self)->&str{
+		if self.user2_is_self{
+			self.user1_id.as_str()
+		}else{
+			self.user2_id.as_str()
+		}
+	}
+	/**@username@host*/
+	fn opponent_name(&self)->Option<String>{
+		self.opponent.as_ref().map(|u|match u.host.as_ref(){
+			Some(host)=>format!("@{}@{}",u.username,host),
+			None=>format!("@{}",u.username),
+		})
+	}
 	fn is_self_turn(&self)->bool{
-		self.user2_is_active_player==self.user2_is_self
+		self.turn==Turn::of(self.is_self_black())
 	}
 	fn is_self_black(&self)->bool{
 		self.user2_is_black==self.user2_is_self
 	}
-	async fn call_dekunobou_ffi(&mut self,config:&ConfigFile)->Option<u32>{
-		let depth=config.depth;
-		let perfect_search_depth=config.perfect_search_depth;
-		let board_string = std::ffi::CString::new(self.board.0.as_str()).unwrap();
-		let pos=unsafe { dekunobou::dekunobou(board_string.as_ptr(),!self.is_self_black(),depth,perfect_search_depth) };
-		Some(pos)
-	}
-	async fn call_dekunobou_http(&mut self,client:&Client,config:&ConfigFile)->Option<u32>{
-		println!("call_dekunobou");
-		let mut req=DekunobouRequest{
-			board:self.board.clone(),
-			depth: 8,
-			perfect_search_depth: 13,
-			turn: if self.is_self_black(){
-				0
-			}else{
-				1
-			},
+	/**is_blackの番になった時の手番 置けなければパスして相手の番 どちらも置けなければ終局*/
+	fn turn_from(&self,is_black:bool)->Turn{
+		if !self.board.legal_move_list(is_black,&self.rules).is_empty(){
+			Turn::of(is_black)
+		}else if !self.board.legal_move_list(!is_black,&self.rules).is_empty(){
+			tracing::info!(ply=self.log.len(),black=is_black,"pass");
+			Turn::of(!is_black)
+		}else{
+			Turn::Over
+		}
+	}
+	/**手を打った側から見て次の手番を決める 相手が置けなければもう一度同じ側の番*/
+	fn update_turn(&mut self,mover_is_black:bool){
+		self.turn=self.turn_from(!mover_is_black);
+	}
+	/**サーバーから届いたlogを反映する 手元の盤と食い違っていればfalse*/
+	fn apply_log(&mut self,pos:u16,player_is_black:Option<bool>,time:Option<u64>,id:Option<&str>)->bool{
+		if id.is_some()&&id==self.acked_id.as_deref(){
+			tracing::debug!(pos,id=?id,"duplicate log of the sent move");
+			return true;
+		}
+		if let Some(pending)=self.pending.as_ref(){
+			let is_self=player_is_black.map(|b|b==self.is_self_black());
+			match (id,is_self){
+				//送った手はidで突き合わせる
+				(Some(id),_) if id==pending.id=>{
+					if pending.pos!=pos{
+						tracing::warn!(ply=self.log.len(),pos=pending.pos,id=%pending.id,logged_pos=pos,"sent move logged at another position");
+						return false;
+					}
+					self.ack_pending();
+					return true;
+				},
+				//別のidで自分の手が記録された
+				(Some(_),Some(true))=>{
+					tracing::warn!(ply=self.log.len(),pos=pending.pos,id=%pending.id,logged_pos=pos,logged_id=?id,"move rejected by server");
+					return false;
+				},
+				//idの付かないlogは位置が同じなら送った手とみなす
+				(None,Some(true)|None) if pending.pos==pos=>{
+					self.ack_pending();
+					return true;
+				},
+				//相手の手が届いたなら送った手は受理されているので普通の手として続ける
+				_=>{
+					tracing::debug!(pos=pending.pos,"sent move accepted without its log");
+					self.ack_pending();
+				},
+			}
+		}
+		let is_black=player_is_black.unwrap_or(self.turn==Turn::Black);
+		if self.turn!=Turn::of(is_black){
+			//手番の判断がサーバーと食い違っている
+			tracing::warn!(ply=self.log.len(),turn=?self.turn,black=is_black,"turn mismatch");
+			return false;
+		}
+		if !self.board.legal_move_list(is_black,&self.rules).contains(&pos){
+			return false;
+		}
+		tracing::info!(ply=self.log.len()+1,pos,black=is_black,"opponent move");
+		self.board.put_stone(pos,is_black,&self.rules);
+		self.log.push(kifu::KifuMove{
+			pos,
+			is_black,
+			time:time.unwrap_or_else(kifu::now_millis),
+		});
+		self.synced+=1;
+		self.update_turn(is_black);
+		true
+	}
+	/**確認待ちの手がサーバーに受理された*/
+	fn ack_pending(&mut self){
+		if let Some(pending)=self.pending.take(){
+			self.synced+=1;
+			self.acked_id=Some(pending.id);
+		}
+	}
+	/**サーバーの対局情報から盤面と手番を作り直す*/
+	fn apply_detail(&mut self,detail:&ReversiGameDetail)->Result<(),String>{
+		//扱えない盤なら何も変えずに返す
+		let board=if detail.map.is_empty(){
+			MiBoard::new()
+		}else{
+			MiBoard::from_map(&detail.map).ok_or_else(||format!("unsupported map {:?}",detail.map))?
 		};
-		for _ in 0..3{
-			let v={
-				//dekunobou return int 32bit
-				let res=client.put(config.dekunobou.as_ref()?.as_str()).header("Content-Type","application/json").body(serde_json::to_string(&req).unwrap()).send().await;
-				let res=res.map_err(|e|eprintln!("{:?}",e)).ok()?;
-				let res=res.bytes().await.map_err(|e|eprintln!("{:?}",e)).ok()?;
-				serde_json::from_slice::<DekunobouResponse>(&res).map_err(|e|eprintln!("{:?}",e)).ok()
+		self.rules=detail.rules;
+		self.clock.set_turn_limit(detail.time_limit_for_each_turn);
+		if let Some(black)=detail.black{
+			self.user2_is_black=black==2;
+		}
+		self.board=board;
+		self.initial_board=self.board.clone();
+		if detail.is_started&&self.started_at.is_none(){
+			self.started_at=Some(kifu::now_millis());
+		}
+		self.log.clear();
+		//黒が先手
+		self.turn=self.turn_from(true);
+		let mut time=self.started_at.unwrap_or(0);
+		for log in detail.logs.iter(){
+			//操作は0(put)のみ
+			if let [delta,player,0,pos]=log.as_slice(){
+				let is_black=*player==1;
+				time+=*delta as u64;
+				self.board.put_stone_silent(*pos as u16,is_black,&self.rules);
+				self.log.push(kifu::KifuMove{
+					pos:*pos as u16,
+					is_black,
+					time,
+				});
+			}
+		}
+		//途中でパスがあってもサーバーのlogの手番に従う
+		if let Some(is_black)=self.log.last().map(|m|m.is_black){
+			self.update_turn(is_black);
+		}
+		self.synced=self.log.len();
+		//サーバーのlogが正なので確認待ちの手は捨てる
+		self.pending=None;
+		Ok(())
+	}
+	/**始まっていてどちらも置けなければ終局*/
+	fn is_game_over(&self)->bool{
+		self.started_at.is_some()&&self.turn==Turn::Over
+	}
+	fn engine_name(&self)->String{
+		if self.engines_used.is_empty(){
+			"none".to_owned()
+		}else{
+			self.engines_used.join("+")
+		}
+	}
+	/**盤面から勝者を決める 自分が投了したなら相手の勝ち*/
+	fn judge(&self,reason:&str)->Option<String>{
+		let (black,white)=self.board.count();
+		if reason=="surrender"{
+			Some(self.opponent_id().to_owned())
+		}else{
+			let (self_count,opponent_count)=if self.is_self_black(){
+				(black,white)
+			}else{
+				(white,black)
 			};
-			req.depth-=1;
-			req.perfect_search_depth-=1;
-			if let Some(v)=v{
-				return u32::from_str_radix(&v.pos,10).ok();
+			//ロセオは石が少ない方が勝ち
+			let self_won=if self.rules.is_llotheo{
+				self_count<opponent_count
+			}else{
+				self_count>opponent_count
+			};
+			if self_count==opponent_count{
+				None
+			}else if self_won{
+				Some(self.self_id().to_owned())
+			}else{
+				Some(self.opponent_id().to_owned())
 			}
 		}
-		None
 	}
-	async fn put_stone_and_loop(&mut self,client:&Client,ws:&mut WSState,config:&ConfigFile){
-		if !self.is_self_turn(){
-			return;
+	fn make_record(&self,config:&ConfigFile,reason:&str,winner_id:Option<String>)->kifu::GameRecord{
+		let (black,white)=self.board.count();
+		let mut url=reqwest::Url::parse(config.instance.as_ref()).unwrap();
+		url.set_path(&format!("reversi/g/{}",self.id));
+		kifu::GameRecord{
+			game_id:self.id.clone(),
+			url:url.to_string(),
+			self_id:self.self_id().to_owned(),
+			opponent_id:self.opponent_id().to_owned(),
+			opponent:self.opponent_name(),
+			self_is_black:self.is_self_black(),
+			rules:self.rules,
+			initial_board:self.initial_board.clone(),
+			moves:self.log.clone(),
+			engine:self.engine_name(),
+			depth:self.profile.depth,
+			perfect_search_depth:self.profile.perfect_search_depth,
+			started_at:self.started_at.unwrap_or(0),
+			ended_at:kifu::now_millis(),
+			result:kifu::GameResult{
+				black,
+				white,
+				winner_id,
+				reason:reason.to_owned(),
+			},
 		}
-		loop{
-			//自分の視点で置けるか確認する
-			let list=MiBoard::from(self.board.clone()).legal_move_list(self.is_self_black());
-			if list.is_empty(){
-				//どこにも置けないなら自分の番を終了
-				println!("どこにも置けないなら自分の番を終了");
-				break;
+	}
+	/**初めて呼ばれた時だけ棋譜を保存して返す*/
+	fn save_record(&mut self,config:&ConfigFile,reason:&str,winner_id:Option<String>)->Option<kifu::GameRecord>{
+		if self.recorded{
+			return None;
+		}
+		self.recorded=true;
+		let record=self.make_record(config,reason,winner_id);
+		let dir=config.record_dir.as_deref().unwrap_or("records");
+		match kifu::save(dir,&record){
+			Ok(_)=>tracing::info!(dir,transcript=%kifu::transcript(&record),"saved record"),
+			Err(e)=>tracing::error!(dir,"save record error {:?}",e),
+		}
+		Some(record)
+	}
+	/**棋譜を保存して成績に反映し結果を投稿する*/
+	async fn finish(&mut self,client:&Client,config:&ConfigFile,state:&BotState,reason:&str,winner_id:Option<String>){
+		if let Some(record)=self.save_record(config,reason,winner_id){
+			let host=self.opponent.as_ref().and_then(|u|u.host_or_instance(&config.instance));
+			state.stats.lock().await.record(&record,host);
+			note::post_result(config,client,self,&record).await;
+		}
+	}
+	async fn fetch_detail(&self,client:&Client,config:&ConfigFile)->Option<ReversiGameDetail>{
+		let mut url=reqwest::Url::parse(config.instance.as_ref()).unwrap();
+		url.set_path("api/reversi/show-game");
+		let req=ShowGameRequest{
+			i:config.token.clone(),
+			game_id:self.id.clone(),
+		};
+		let res=client.post(url).header("Content-Type","application/json").body(serde_json::to_string(&req).unwrap()).send().await;
+		let res=res.and_then(|res|res.error_for_status()).map_err(|e|tracing::error!("show-game error {:?}",e)).ok()?;
+		let res=res.bytes().await.map_err(|e|tracing::error!("show-game error {:?}",e)).ok()?;
+		serde_json::from_slice::<ReversiGameDetail>(&res).map_err(|e|tracing::error!("show-game parse error {:?}",e)).ok()
+	}
+	/**サーバーの状態を取り直して盤面を合わせる 対局が始まっていなければfalse 扱えない盤なら投了する*/
+	async fn resync(&mut self,client:&Client,config:&ConfigFile)->bool{
+		tracing::info!(ply=self.log.len(),"resync");
+		match self.fetch_detail(client,config).await{
+			Some(detail)=>{
+				if let Err(e)=self.apply_detail(&detail){
+					tracing::warn!("{}",e);
+					self.surrender(client,config).await;
+					return false;
+				}
+				self.board.debug_dump();
+				detail.is_started
+			},
+			None=>false,
+		}
+	}
+	/**自分の番なら対局のイベントを受けながら待てるよう別のタスクで探索を始める*/
+	//相手がパスして続けて打つ時は送った手がlogで確認できてから次を読む
+	fn start_turn(&mut self,config:&ConfigFile,state:&Arc<BotState>)->Option<SearchTask>{
+		if self.surrendered||self.started_at.is_none()||!self.is_self_turn(){
+			return None;
+		}
+		if self.pending.is_some(){
+			tracing::debug!("waiting for log of the sent move");
+			return None;
+		}
+		self.search_profile=self.clock.profile(&self.profile,config.move_time_limit);
+		let budget=self.clock.budget(config.move_time_limit);
+		let limits=engine::Limits{
+			depth:self.search_profile.depth,
+			perfect_search_depth:self.search_profile.perfect_search_depth,
+			time_millis:budget,
+		};
+		let game_id=self.id.clone();
+		let board=self.board.clone();
+		let is_black=self.is_self_black();
+		let rules=self.rules;
+		let ply=self.log.len();
+		let state=state.clone();
+		let span=tracing::info_span!("move",ply=ply+1);
+		Some(tokio::runtime::Handle::current().spawn(async move{
+			let position=engine::Position{
+				game_id:&game_id,
+				board:&board,
+				is_black,
+				rules:&rules,
+				ply,
+			};
+			let started=std::time::Instant::now();
+			let res=match budget{
+				Some(budget)=>tokio::time::timeout(tokio::time::Duration::from_millis(budget),state.engine.search(&position,&limits)).await.ok(),
+				None=>Some(state.engine.search(&position,&limits).await),
+			};
+			match res{
+				Some(res)=>SearchDone{
+					ply,
+					res,
+					elapsed:started.elapsed().as_millis() as u64,
+					timed_out:false,
+				},
+				None=>{
+					//間に合わなかったので手元の浅い探索で打つ
+					tracing::warn!(budget_millis=budget,"search timed out, playing fallback move");
+					//短い時間でもスレッドを占有するのでtokioのワーカーでは読まない
+					let span=tracing::Span::current();
+					let pos=tokio::task::spawn_blocking(move||span.in_scope(||search::fallback_move(&board,is_black,&rules))).await.ok().flatten();
+					SearchDone{
+						ply,
+						res:pos.map(|pos|engine::SearchResult::new(pos,"fallback")),
+						elapsed:budget.unwrap_or(0),
+						timed_out:true,
+					}
+				},
 			}
-			self.put_stone(client, ws,config).await;
-			//相手の視点で置けるか確認する
-			let list=MiBoard::from(self.board.clone()).legal_move_list(!self.is_self_black());
-			if !list.is_empty(){
-				println!("どこかに置けるなら自分の番は終了{:?}",list);
-				//どこかに置けるなら自分の番は終了
-				break;
+		}.instrument(span)))
+	}
+	/**確認待ちの手を同じidで送る*/
+	async fn send_pending(&mut self,ws:&mut WSState){
+		let pending=match self.pending.as_mut(){
+			Some(pending)=>pending,
+			None=>return,
+		};
+		pending.attempts+=1;
+		pending.sent_at=kifu::now_millis();
+		let mut map=serde_json::Map::new();
+		map.insert("id".into(),pending.id.clone().into());
+		map.insert("pos".into(),pending.pos.into());
+		if let Err(e)=ws.send_channel("putStone".to_string(),Some(serde_json::Value::Object(map))).await{
+			tracing::warn!("putStone error {}",e);
+		}
+	}
+	/**確認待ちの手のlogが届くまでの残り時間*/
+	fn ack_wait(&self)->Option<tokio::time::Duration>{
+		self.pending.as_ref().map(|pending|tokio::time::Duration::from_millis((pending.sent_at+ACK_TIMEOUT_MILLIS).saturating_sub(kifu::now_millis())))
+	}
+	/**確認が取れないまま待ち時間が過ぎたら送り直す 何度送っても駄目ならサーバーの状態に合わせる*/
+	async fn on_ack_timeout(&mut self,client:&Client,ws:&mut WSState,config:&ConfigFile){
+		let (pos,index)=match self.ack_timed_out(){
+			Some(AckTimeout::Resend)=>{
+				self.send_pending(ws).await;
+				return;
+			},
+			Some(AckTimeout::Resync{pos,index})=>(pos,index),
+			None=>return,
+		};
+		if self.resync(client,config).await{
+			match self.log.get(index){
+				Some(m) if m.pos==pos&&m.is_black==self.is_self_black()=>tracing::info!(pos,"sent move was accepted"),
+				_=>tracing::warn!(pos,"sent move was not accepted by server"),
 			}
 		}
 	}
-	async fn put_stone(&mut self,client:&Client,ws:&mut WSState,config:&ConfigFile){
-		if !self.is_self_turn(){
+	/**確認待ちの手の待ち時間が過ぎた時にすること*/
+	fn ack_timed_out(&mut self)->Option<AckTimeout>{
+		let synced=self.synced;
+		let pending=self.pending.as_mut()?;
+		if pending.attempts<MAX_SEND_ATTEMPTS{
+			tracing::warn!(pos=pending.pos,id=%pending.id,attempt=pending.attempts+1,"no log for the sent move, resending");
+			return Some(AckTimeout::Resend);
+		}
+		//取り直せなかった時にすぐ次を試さないように待ち時間を延ばす
+		pending.sent_at=kifu::now_millis();
+		Some(AckTimeout::Resync{
+			pos:pending.pos,
+			index:synced,
+		})
+	}
+	/**自分の手を手元の盤に打ち logで確認が取れるまで同じidで送り直す*/
+	fn put_own_move(&mut self,pos:u16,id:String){
+		self.board.put_stone(pos,self.is_self_black(),&self.rules);
+		self.board.debug_dump();
+		self.log.push(kifu::KifuMove{
+			pos,
+			is_black:self.is_self_black(),
+			time:kifu::now_millis(),
+		});
+		self.update_turn(self.is_self_black());
+		self.pending=Some(PendingMove{
+			id,
+			pos,
+			attempts:0,
+			sent_at:0,
+		});
+	}
+	/**探索の結果の手を打つ 手が得られなければ投了する*/
+	async fn play(&mut self,done:SearchDone,client:&Client,ws:&mut WSState,config:&ConfigFile){
+		//読んでいる間に盤面を取り直していたら古い局面の手なので捨てる
+		if self.surrendered||done.ply!=self.log.len()||!self.is_self_turn(){
+			tracing::debug!(ply=done.ply,"discard stale search result");
 			return;
 		}
-		let mut map=serde_json::Map::new();
-		let res=if config.dekunobou.is_some(){
-			self.call_dekunobou_http(&client,config).await
+		if done.timed_out{
+			self.clock.timed_out(done.elapsed);
 		}else{
-			self.call_dekunobou_ffi(&config).await
+			self.clock.record(done.elapsed);
+		}
+		let res=done.res;
+		if let Some(res)=res.as_ref(){
+			tracing::info!(engine=%res.engine,pos=res.pos,eval=?res.eval,pv=?res.pv,"move");
+			if !self.engines_used.contains(&res.engine){
+				self.engines_used.push(res.engine.clone());
+			}
+		}
+		//定石以外は設定に応じて手を緩める
+		let res=match res{
+			Some(res) if res.engine!="book"=>{
+				let legal_moves=self.board.legal_move_list(self.is_self_black(),&self.rules);
+				Some(strength::handicap_move(&self.profile,res.pos,&legal_moves))
+			},
+			res=>res.map(|res|res.pos),
 		};
 		match res{
 			Some(pos)=>{
 				use rand::distributions::{Alphanumeric, DistString};
 				let mut rng=rand::rngs::StdRng::from_entropy();
 				let id = Alphanumeric.sample_string(&mut rng, 10).to_ascii_lowercase();
-				map.insert("id".into(),id.into());
-				if let Ok(pos)=u8::from_str_radix(pos.to_string().as_str(),10){
-					self.board.put_stone(pos,self.is_self_black());
-					self.board.debug_dump();
-					self.log.push(pos);
-					map.insert("pos".into(),serde_json::Value::Number(pos.into()));
-					for _ in 0..2{
-						let res=ws.send_channel("putStone".to_string(),Some(serde_json::Value::Object(map.clone()))).await;
-						if let Err(e)=res{
-							println!("putStone err{:}",e);
-						}else{
-							tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-							break;
-						}
-					}
-				}
+				self.put_own_move(pos,id);
+				self.send_pending(ws).await;
 			},
 			None=>{
-				//投了する(未実装)
+				//手が得られなかったので投了する
+				self.surrender(client,config).await;
+			}
+		}
+	}
+	async fn surrender(&mut self,client:&Client,config:&ConfigFile){
+		if self.surrendered{
+			return;
+		}
+		tracing::info!(ply=self.log.len(),"surrender");
+		let mut url=reqwest::Url::parse(config.instance.as_ref()).unwrap();
+		url.set_path("api/reversi/surrender");
+		let req=SurrenderRequest{
+			i:config.token.clone(),
+			game_id:self.id.clone(),
+		};
+		let mut backoff=tokio::time::Duration::from_secs(1);
+		for attempt in 1..=SURRENDER_ATTEMPTS{
+			let res=client.post(url.clone()).header("Content-Type","application/json").body(serde_json::to_string(&req).unwrap()).send().await;
+			match res.and_then(|res|res.error_for_status()){
+				Ok(_)=>{
+					self.surrendered=true;
+					return;
+				},
+				Err(e)=>{
+					tracing::warn!(attempt,"surrender error {:?}",e);
+				}
+			}
+			if attempt<SURRENDER_ATTEMPTS{
+				tokio::time::sleep(backoff).await;
+				backoff*=2;
+			}
+		}
+		//投了できないまま対局を続ける
+		tracing::error!(attempts=SURRENDER_ATTEMPTS,"surrender failed, game continues");
+	}
+}
+/**進行中の対局*/
+struct GameHandle{
+	/**投了の要求 対局のイベントが詰まっていても取りこぼさないようpermitで残す*/
+	surrender:Arc<tokio::sync::Notify>,
+	opponent_id:String,
+}
+/**進行中の対局へローカルイベントを送るための共有状態*/
+struct BotState{
+	/**reload configで差し替える*/
+	config:std::sync::RwLock<Arc<ConfigFile>>,
+	games:Mutex<HashMap<String,GameHandle>>,
+	/**定石やdekunobouなど手を選ぶエンジン*/
+	engine:engine::Chain,
+	invite_history:policy::InviteHistory,
+	/**max_concurrent_gamesの判定に使う対局数*/
+	game_slots:policy::GameSlots,
+	/**ランダムマッチの待ち行列に並んでいる間に取っておく枠*/
+	random_slot:Mutex<Option<policy::GameSlot>>,
+	/**bot自身のユーザーid*/
+	self_id:String,
+	/**ランダムマッチの待ち行列に並んでいるか*/
+	in_random_queue:AtomicBool,
+	/**こちらから申し込んで返事を待っている相手と申し込んだ時刻*/
+	outgoing_invites:Mutex<HashMap<String,std::time::Instant>>,
+	/**ランダムマッチで始まった対局*/
+	random_game:Mutex<Option<String>>,
+	/**招待の受け付けを止めているか*/
+	paused:AtomicBool,
+	/**play meを頼まれたユーザーidとノートのid*/
+	play_requests:Mutex<HashMap<String,String>>,
+	/**stronger/weakerで変えたユーザーごとの強さ*/
+	strength_offsets:Mutex<HashMap<String,i32>>,
+	stats:Mutex<stats::StatsStore>,
+}
+impl BotState{
+	fn new(config:&Arc<ConfigFile>,client:&Client,self_id:String)->Self{
+		let book=config.book.as_ref().and_then(|book|book::OpeningBook::load(book).map_err(|e|tracing::error!("book load error {:?}",e)).ok());
+		let stats_file=config.stats_file.clone().unwrap_or(format!("{}/stats.json",config.record_dir.as_deref().unwrap_or("records")));
+		Self{
+			config:std::sync::RwLock::new(config.clone()),
+			games:Mutex::new(HashMap::new()),
+			engine:engine::build(config,client,book),
+			invite_history:policy::InviteHistory::new(),
+			game_slots:policy::GameSlots::new(),
+			random_slot:Mutex::new(None),
+			self_id,
+			in_random_queue:AtomicBool::new(false),
+			outgoing_invites:Mutex::new(HashMap::new()),
+			random_game:Mutex::new(None),
+			paused:AtomicBool::new(false),
+			play_requests:Mutex::new(HashMap::new()),
+			strength_offsets:Mutex::new(HashMap::new()),
+			stats:Mutex::new(stats::StatsStore::load(&stats_file)),
+		}
+	}
+	fn config(&self)->Arc<ConfigFile>{
+		self.config.read().unwrap().clone()
+	}
+	/**config.jsonを読み直す 接続先とトークン,定石やエンジン,ログの設定は起動時のまま*/
+	//sparring,random_match,commandは中身の変更はすぐ使うが 起動時に無かったものを有効にするには再起動が要る
+	fn reload_config(&self)->Result<(),String>{
+		let config=load_config()?;
+		*self.config.write().unwrap()=Arc::new(config);
+		tracing::info!("config reloaded");
+		Ok(())
+	}
+	/**指定した対局に投了を要求する Noneなら全ての対局*/
+	async fn request_surrender(&self,game_id:Option<&str>)->usize{
+		self.send_surrender(|id,_|game_id.map(|g|g==id).unwrap_or(true)).await
+	}
+	/**userとの対局に投了を要求する*/
+	async fn request_surrender_against(&self,user_id:&str)->usize{
+		self.send_surrender(|_,game|game.opponent_id==user_id).await
+	}
+	async fn send_surrender(&self,filter:impl Fn(&str,&GameHandle)->bool)->usize{
+		let games=self.games.lock().await;
+		let mut count=0;
+		for (id,game) in games.iter(){
+			if filter(id,game){
+				game.surrender.notify_one();
+				count+=1;
 			}
 		}
+		count
 	}
 }
 #[derive(Serialize,Deserialize,Debug)]
 struct ReversiStarted{
 	black:u8,
 }
-#[derive(Serialize,Deserialize,Debug)]
-struct MiBoard([[u8;8];8]);
+//get_flip_limitの方向順(左,右,上,下,右上,右下,左上,左下)
+const DIR_I:[isize;8]=[0,0,-1,1,-1,1,-1,1];
+const DIR_J:[isize;8]=[-1,1,0,0,1,1,-1,-1];
+/**置けないマス*/
+const WALL:u8=3;
+/**盤面 置けないマスがない8x8はbitboardだけで持ち それ以外のマップはマスの並びで持つ*/
+#[derive(Clone,Serialize,Deserialize,Debug)]
+#[serde(from="BoardCells",into="BoardCells")]
+enum MiBoard{
+	Bits(bitboard::Bitboard),
+	Grid{
+		width:usize,
+		height:usize,
+		/**0:空き 1:黒 2:白 3:置けないマス*/
+		cells:Vec<u8>,
+	},
+}
+/**棋譜などに保存する形 読み込む時に8x8ならbitboardに戻す*/
+#[derive(Clone,Serialize,Deserialize,Debug)]
+struct BoardCells{
+	width:usize,
+	height:usize,
+	cells:Vec<u8>,
+}
+impl From<BoardCells> for MiBoard{
+	fn from(value:BoardCells)->Self{
+		Self::with_cells(value.width,value.height,value.cells)
+	}
+}
+impl From<MiBoard> for BoardCells{
+	fn from(value:MiBoard)->Self{
+		Self{
+			width:value.width(),
+			height:value.height(),
+			cells:value.cells().collect(),
+		}
+	}
+}
 impl MiBoard {
-	fn put_stone(&mut self,pos:u8,is_black:bool){
-		if pos>64{
+	/**通常の8x8の初期配置*/
+	fn new()->Self{
+		Self::from(DekunobouBoard::new())
+	}
+	/**Misskeyのマップ('-':空き 'b':黒 'w':白 ' ':置けないマス)から盤を作る*/
+	fn from_map(map:&[String])->Option<Self>{
+		let height=map.len();
+		let width=map.first()?.chars().count();
+		//マスの位置はu16で送り受けするのでそれを超える盤だけは扱えない
+		if width==0||width*height>u16::MAX as usize{
+			return None;
+		}
+		let mut cells=Vec::with_capacity(width*height);
+		for line in map{
+			if line.chars().count()!=width{
+				return None;
+			}
+			for c in line.chars(){
+				cells.push(match c{
+					'-'=>0,
+					'b'=>1,
+					'w'=>2,
+					' '=>WALL,
+					_=>return None,
+				});
+			}
+		}
+		Some(Self::with_cells(width,height,cells))
+	}
+	/**置けないマスがない8x8ならbitboardにする*/
+	fn with_cells(width:usize,height:usize,cells:Vec<u8>)->Self{
+		if width==8&&height==8{
+			if let Some(b)=bitboard::Bitboard::from_cells(&cells){
+				return MiBoard::Bits(b);
+			}
+		}
+		MiBoard::Grid{
+			width,
+			height,
+			cells,
+		}
+	}
+	fn width(&self)->usize{
+		match self{
+			MiBoard::Bits(_)=>8,
+			MiBoard::Grid{width,..}=>*width,
+		}
+	}
+	fn height(&self)->usize{
+		match self{
+			MiBoard::Bits(_)=>8,
+			MiBoard::Grid{height,..}=>*height,
+		}
+	}
+	/**マスの数*/
+	fn len(&self)->usize{
+		self.width()*self.height()
+	}
+	/**0:空き 1:黒 2:白 3:置けないマス*/
+	fn cell_at(&self,pos:usize)->u8{
+		match self{
+			MiBoard::Bits(b)=>b.cell(pos as u16),
+			MiBoard::Grid{cells,..}=>cells[pos],
+		}
+	}
+	fn cell(&self,row:usize,col:usize)->u8{
+		self.cell_at(row*self.width()+col)
+	}
+	/**左上から行ごとに全てのマス*/
+	fn cells(&self)->impl Iterator<Item=u8>+'_{
+		(0..self.len()).map(|pos|self.cell_at(pos))
+	}
+	/**マスの色を変える*/
+	fn set_cell(&mut self,pos:usize,color:u8){
+		match self{
+			MiBoard::Bits(b)=>{
+				b.black&=!(1<<pos);
+				b.white&=!(1<<pos);
+				match color{
+					1=>b.black|=1<<pos,
+					2=>b.white|=1<<pos,
+					_=>{},
+				}
+			},
+			MiBoard::Grid{cells,..}=>cells[pos]=color,
+		}
+	}
+	/**dekunobouに渡せる盤面(8x8で置けないマスがない)ならその文字列*/
+	fn to_dekunobou(&self)->Option<DekunobouBoard>{
+		Some(self.to_bitboard()?.to_dekunobou())
+	}
+	/**置けないマスがない8x8ならbitboard*/
+	fn to_bitboard(&self)->Option<bitboard::Bitboard>{
+		match self{
+			MiBoard::Bits(b)=>Some(*b),
+			MiBoard::Grid{..}=>None,
+		}
+	}
+	/**通常の挟み方で8x8ならbitboardで計算する*/
+	fn bitboard_for(&self,rules:&GameRules)->Option<bitboard::Bitboard>{
+		if rules.can_put_everywhere||rules.looped_board{
+			return None;
+		}
+		self.to_bitboard()
+	}
+	fn debug_dump(&self){
+		let mut s=String::new();
+		for (i,c) in self.cells().enumerate(){
+			s.push(match c{
+				1=>'@',
+				2=>'X',
+				WALL=>' ',
+				_=>'_',
+			});
+			if i%self.width()==self.width()-1{
+				s.push('\n');
+			}
+		}
+		tracing::debug!("board\n{}",s);
+	}
+	fn put_stone(&mut self,pos:u16,is_black:bool,rules:&GameRules){
+		let (flip_limit,flip_count)=self.put_stone_silent(pos,is_black,rules);
+		tracing::trace!(pos,flip_count,flip_limit=?flip_limit,"put stone");
+	}
+	/**探索用 ログを出さずに石を置く*/
+	fn put_stone_silent(&mut self,pos:u16,is_black:bool,rules:&GameRules)->([usize;8],usize){
+		if pos as usize>=self.len(){
 			panic!();
 		}
-		let col=(pos%8) as usize;
-		let row=(pos/8) as usize;
-
-		self.0[row][col]=if is_black{
+		let color=if is_black{
 			1
 		}else{
 			2
 		};
-		let di=[0,0,-1,1,-1,1,-1,1];
-		let dj=[-1,1,0,0,1,1,-1,-1];
-		let (flip_limit,flip_count)=self.get_flip_limit(row,col,is_black);
-		println!("flip_count {} {:?}",flip_count,flip_limit);
+		if let Some(mut b)=self.bitboard_for(rules){
+			let flips=b.flips_by_dir(pos,is_black);
+			let mut flip_limit=[0usize;8];
+			for (dir,f) in flips.iter().enumerate(){
+				if *f!=0{
+					flip_limit[dir]=f.count_ones() as usize+1;
+				}
+			}
+			let flips=flips.iter().fold(0,|a,f|a|f);
+			b.apply(pos,flips,is_black);
+			*self=MiBoard::Bits(b);
+			return (flip_limit,flips.count_ones() as usize);
+		}
+		let col=pos as usize%self.width();
+		let row=pos as usize/self.width();
+
+		let (flip_limit,flip_count)=self.get_flip_limit(row,col,is_black,rules);
+		self.set_cell(pos as usize,color);
 		for dir in 0..8{
 			for i in 1..flip_limit[dir]{
-				self.0[(row as isize+di[dir]*i as isize) as usize][(col as isize+dj[dir]*i as isize) as usize]=self.0[row][col];
+				//ループ盤面では反対側に回り込む
+				let r=(row as isize+DIR_I[dir]*i as isize).rem_euclid(self.height() as isize) as usize;
+				let c=(col as isize+DIR_J[dir]*i as isize).rem_euclid(self.width() as isize) as usize;
+				self.set_cell(r*self.width()+c,color);
 			}
 		}
+		(flip_limit,flip_count)
 	}
-	fn legal_move_list(&self,is_black:bool)->Vec<u8>{
+	fn legal_move_list(&self,is_black:bool,rules:&GameRules)->Vec<u16>{
+		if let Some(b)=self.bitboard_for(rules){
+			return bitboard::bits(b.legal_moves(is_black)).collect();
+		}
 		let mut movelist=Vec::new();
-		for i in 0..8{
-			for j in 0..8{
-				if self.0[i][j]==0{
-					let (flip_limit,_)=self.get_flip_limit(i,j,is_black);
-					for k in 0..8{
-						if flip_limit[k]>1{
-							movelist.push((8*i+j) as u8);
-							break;
-						}
+		for i in 0..self.height(){
+			for j in 0..self.width(){
+				if self.cell(i,j)==0{
+					if rules.can_put_everywhere{
+						//挟めなくても空いていれば置ける
+						movelist.push((self.width()*i+j) as u16);
+						continue;
+					}
+					let (_,flip_count)=self.get_flip_limit(i,j,is_black,rules);
+					if flip_count>0{
+						movelist.push((self.width()*i+j) as u16);
 					}
 				}
 			}
 		}
 		movelist
 	}
+	/**黒石と白石の数*/
+	fn count(&self)->(u32,u32){
+		if let Some(b)=self.to_bitboard(){
+			return b.count();
+		}
+		let mut black=0;
+		let mut white=0;
+		for c in self.cells(){
+			match c{
+				1=>black+=1,
+				2=>white+=1,
+				_=>{},
+			}
+		}
+		(black,white)
+	}
+	/**ループ盤面用 盤端で反対側に回り込み,置く位置まで戻ってきたらそこまでの石を挟めたとみなす*/
+	fn get_flip_limit_looped(&self,row:usize,col:usize,is_black:bool)->([usize;8],usize){
+		let self_color=if is_black{
+			1
+		}else{
+			2
+		};
+		let enemy_color=if is_black{
+			2
+		}else{
+			1
+		};
+		let mut flip_count=0;
+		let mut flip_limit=[0usize;8];
+		for dir in 0..8{
+			let mut i=1;
+			loop{
+				let r=(row as isize+DIR_I[dir]*i as isize).rem_euclid(self.height() as isize) as usize;
+				let c=(col as isize+DIR_J[dir]*i as isize).rem_euclid(self.width() as isize) as usize;
+				if r==row&&c==col{
+					flip_limit[dir]=i;
+					break;
+				}
+				if self.cell(r,c)!=enemy_color{
+					if self.cell(r,c)==self_color{
+						flip_limit[dir]=i;
+					}
+					break;
+				}
+				i+=1;
+			}
+			if flip_limit[dir]>1{
+				flip_count+=flip_limit[dir]-1;
+			}
+		}
+		(flip_limit,flip_count)
+	}
 
-	fn get_flip_limit(&self,row:usize,col:usize,is_black:bool)->([usize;8],usize){
+	fn get_flip_limit(&self,row:usize,col:usize,is_black:bool,rules:&GameRules)->([usize;8],usize){
+		if rules.looped_board{
+			return self.get_flip_limit_looped(row,col,is_black);
+		}
 		let self_color=if is_black{
 			1
 		}else{
@@ -243,8 +1185,8 @@ impl MiBoard {
 		let mut flip_limit=[0usize;8];
 		flip_limit[0]=0;
 		for i in 1..(col+1){
-			if self.0[row][col-i]!=enemy_color{
-				if self.0[row][col-i]==self_color{
+			if self.cell(row,col-i)!=enemy_color{
+				if self.cell(row,col-i)==self_color{
 					flip_limit[0]=i;
 				}
 				break;
@@ -256,9 +1198,9 @@ impl MiBoard {
 
 		//横右方向
 		flip_limit[1]=0;
-		for i in 1..8-col{
-			if self.0[row][col+i]!=enemy_color{
-				if self.0[row][col+i]==self_color{
+		for i in 1..self.width()-col{
+			if self.cell(row,col+i)!=enemy_color{
+				if self.cell(row,col+i)==self_color{
 					flip_limit[1]=i;
 				}
 				break;
@@ -270,8 +1212,8 @@ impl MiBoard {
 		//縦上方向
 		flip_limit[2]=0;
 		for i in 1..(row+1){
-			if self.0[row-i][col]!=enemy_color{
-				if self.0[row-i][col]==self_color{
+			if self.cell(row-i,col)!=enemy_color{
+				if self.cell(row-i,col)==self_color{
 					flip_limit[2]=i;
 				}
 				break;
@@ -282,9 +1224,9 @@ impl MiBoard {
 		}
 		//縦下方向
 		flip_limit[3]=0;
-		for i in 1..8-row{
-			if self.0[row+i][col]!=enemy_color{
-				if self.0[row+i][col]==self_color{
+		for i in 1..self.height()-row{
+			if self.cell(row+i,col)!=enemy_color{
+				if self.cell(row+i,col)==self_color{
 					flip_limit[3]=i;
 				}
 				break;
@@ -295,9 +1237,9 @@ impl MiBoard {
 		}
 		//右斜め上方向
 		flip_limit[4]=0;
-		for i in 1..(row+1).min(8-col){
-			if self.0[row-i][col+i]!=enemy_color{
-				if self.0[row-i][col+i]==self_color{
+		for i in 1..(row+1).min(self.width()-col){
+			if self.cell(row-i,col+i)!=enemy_color{
+				if self.cell(row-i,col+i)==self_color{
 					flip_limit[4]=i;
 				}
 				break;
@@ -308,9 +1250,9 @@ impl MiBoard {
 		}
 		//右斜め下方向
 		flip_limit[5]=0;
-		for i in 1..(8-row).min(8-col){
-			if self.0[row+i][col+i]!=enemy_color{
-				if self.0[row+i][col+i]==self_color{
+		for i in 1..(self.height()-row).min(self.width()-col){
+			if self.cell(row+i,col+i)!=enemy_color{
+				if self.cell(row+i,col+i)==self_color{
 					flip_limit[5]=i;
 				}
 				break;
@@ -322,8 +1264,8 @@ impl MiBoard {
 		//左斜め上方向
 		flip_limit[6]=0;
 		for i in 1..(row+1).min(col+1){
-			if self.0[row-i][col-i]!=enemy_color{
-				if self.0[row-i][col-i]==self_color{
+			if self.cell(row-i,col-i)!=enemy_color{
+				if self.cell(row-i,col-i)==self_color{
 					flip_limit[6]=i;
 				}
 				break;
@@ -334,9 +1276,9 @@ impl MiBoard {
 		}
 		//左斜め下方向
 		flip_limit[7]=0;
-		for i in 1..(col+1).min(8-row){
-			if self.0[row+i][col-i]!=enemy_color{
-				if self.0[row+i][col-i]==self_color{
+		for i in 1..(col+1).min(self.height()-row){
+			if self.cell(row+i,col-i)!=enemy_color{
+				if self.cell(row+i,col-i)==self_color{
 					flip_limit[7]=i;
 				}
 				break;
@@ -350,39 +1292,17 @@ impl MiBoard {
 }
 impl From<DekunobouBoard> for MiBoard{
 	fn from(value: DekunobouBoard) -> Self {
-		let mut array=[[0u8;8];8];
-		let mut x=0;
-		let mut y=0;
+		let mut cells=Vec::with_capacity(64);
 		for c in value.0.chars(){
 			if c=='1'{
-				array[y][x]=1;
+				cells.push(1);
 			}else if c=='2'{
-				array[y][x]=2;
+				cells.push(2);
 			}else{
-				array[y][x]=0;
-			}
-			x+=1;
-			if x==8{
-				x=0;
-				y+=1;
-			}
-		}
-		Self(array)
-	}
-}
-impl Into<DekunobouBoard> for MiBoard{
-	fn into(self) -> DekunobouBoard {
-		let mut s=String::new();
-		for lines in self.0{
-			for col in lines{
-				match col{
-					2=>s.push('2'),
-					1=>s.push('1'),
-					_=>s.push('0'),
-				}
+				cells.push(0);
 			}
 		}
-		DekunobouBoard(s)
+		Self::with_cells(8,8,cells)
 	}
 }
 #[derive(Clone,Serialize,Deserialize,Debug)]
@@ -392,30 +1312,6 @@ impl DekunobouBoard{
 		//黒1/白2
 		Self("0000000000000000000000000002100000012000000000000000000000000000".into())
 	}
-	fn debug_dump(&self){
-		let mut x=0;
-		let mut s=String::new();
-		for c in self.0.chars(){
-			x+=1;
-			if c=='1'{
-				s.push('@');
-			}else if c=='2'{
-				s.push('X');
-			}else{
-				s.push('_');
-			}
-			if x==8{
-				x=0;
-				s.push('\n');
-			}
-		}
-		println!("{}",s);
-	}
-	fn put_stone(&mut self,pos:u8,is_black:bool){
-		let mut mb:MiBoard=self.clone().into();
-		mb.put_stone(pos,is_black);
-		self.0=Into::<Self>::into(mb).0;
-	}
 	fn update_pos(&self,target:&DekunobouBoard)->u8{
 		let mut index=0;
 		let mut target=target.0.chars();
@@ -430,63 +1326,79 @@ impl DekunobouBoard{
 		index
 	}
 }
-#[derive(Serialize,Deserialize,Debug)]
-struct DekunobouRequest{
-	board:DekunobouBoard,
-	depth:u8,
-	perfect_search_depth:u8,
-	/**黒を置かせる時は0/白を置かせる時は1*/
-	turn:u8,
-}
-#[derive(Serialize,Deserialize,Debug)]
-struct DekunobouResponse{
-	#[serde(rename = "move")]
-	pos:String,
-}
 
-async fn check_invites(config:Arc<ConfigFile>,con:Arc<WSStream>,client:Client){
+async fn check_invites(con:Arc<WSStream>,client:Client,state:Arc<BotState>){
 	let mut ws=WSState::new(con.clone()).await.unwrap();
 	let (s,mut r)=tokio::sync::mpsc::channel(2);
 	ws.open_channel(s, MiChannel::Reversi,None).await.unwrap();
 	while let Some(event)=r.recv().await{
+		//reload configの後は新しい設定で判定する
+		let config=state.config();
 		match event.t.as_str(){
 			"invited"=>{
 				match serde_json::value::from_value::<ReversiInvite>(event.body){
 					Ok(invite)=>{
-						println!("invite from {:?}",&invite.user);
-						let mut url=reqwest::Url::parse(config.instance.as_ref()).unwrap();
-						url.set_path("api/reversi/match");
-						let req=MatchRequest{
-							accept_only:true,
-							i:config.token.clone(),
-							user_id:invite.user.id,
-						};
-						let res=client.post(url).header("Content-Type","application/json").body(serde_json::to_string(&req).unwrap()).send().await;
-						if let Err(e)=res.as_ref(){
-							eprintln!("{:?}",e);
+						tracing::info!(user=%invite.user.id,username=%invite.user.username,host=?invite.user.host,"invite");
+						if state.paused.load(std::sync::atomic::Ordering::SeqCst){
+							tracing::info!(user=%invite.user.id,"paused, ignoring invite");
+							continue;
 						}
-						if let Ok(v)=res.map(|v|v.bytes()){
-							match v.await.map(|b|serde_json::from_slice::<MatchResponse>(&b).map_err(|e|(e,str::from_utf8(&b).map(|s|s.to_owned())))){
-								Ok(Ok(res))=>{
-									tokio::runtime::Handle::current().spawn(join_game(config.clone(),con.clone(),client.clone(),GameContext{
-										id:res.id,
-										user2_is_self:res.user1_id==req.user_id,//1が相手のIdなら,
-										user2_is_black:false,
-										user2_is_active_player:false,
-										user1_id:res.user1_id,
-										user2_id:res.user2_id,
-										board:DekunobouBoard::new(),
-										log:vec![],
-									}));
-								},
-								e=>{
-									eprintln!("{:?}",e);
-								}
+						//同時に届いた招待でも上限を超えないよう先に枠を取る 断ったり始まらなければ捨てる
+						let checked=match state.game_slots.reserve(config.policy.max_concurrent_games){
+							Ok(slot)=>policy::check(&config,&state.invite_history,&invite.user).await.map(|_|slot),
+							Err(reason)=>Err(reason),
+						};
+						let slot=match checked{
+							Ok(slot)=>slot,
+							Err(reason)=>{
+								tracing::info!(user=%invite.user.id,username=%invite.user.username,reason,"invite rejected");
+								continue;
+							}
+						};
+						match request_match(&config,&client,&invite.user.id,true).await{
+							Ok(Some(res))=>{
+								let game=GameContext::from_match(res,&state.self_id,Some(invite.user),&config);
+								spawn_game(&con,&client,&state,game,slot);
+							},
+							Ok(None)=>{
+								tracing::info!(user=%invite.user.id,"invite has gone");
+							},
+							Err(e)=>{
+								tracing::error!(user=%invite.user.id,"reversi/match error {}",e);
 							}
 						}
 					},
 					Err(e)=>{
-						eprintln!("{:?}",e);
+						tracing::warn!("invite parse error {:?}",e);
+					}
+				}
+			},
+			"matched"=>{
+				//こちらからの申し込みが受けられた
+				match event.body.get("game").map(|v|serde_json::value::from_value::<MatchResponse>(v.clone())){
+					Some(Ok(res))=>{
+						if !state.games.lock().await.contains_key(&res.id){
+							//並んでいる間に相手が見つかった
+							//始まってしまった対局なので上限を超えても数える
+							let mut slot=None;
+							let opponent_id=if res.user1_id==state.self_id{
+								&res.user2_id
+							}else{
+								&res.user1_id
+							};
+							//こちらから申し込んだ相手なら並んでいてもランダムマッチの対局ではない
+							let invited=state.outgoing_invites.lock().await.remove(opponent_id).map(|t|t.elapsed()<INVITE_TTL).unwrap_or(false);
+							if !invited&&state.in_random_queue.swap(false,std::sync::atomic::Ordering::SeqCst){
+								*state.random_game.lock().await=Some(res.id.clone());
+								slot=state.random_slot.lock().await.take();
+							}
+							let slot=slot.unwrap_or_else(||state.game_slots.force());
+							let game=GameContext::from_match(res,&state.self_id,None,&config);
+							spawn_game(&con,&client,&state,game,slot);
+						}
+					},
+					e=>{
+						tracing::warn!("matched parse error {:?}",e);
 					}
 				}
 			},
@@ -495,26 +1407,168 @@ async fn check_invites(config:Arc<ConfigFile>,con:Arc<WSStream>,client:Client){
 				return;
 			},
 			_=>{
-				println!("{:?}",event);
+				tracing::debug!(event=?event,"unhandled reversi event");
 			},
 		}
 	}
 }
-async fn join_game(config:Arc<ConfigFile>,con:Arc<WSStream>,client:Client,mut game:GameContext){
-	println!("join {}",game.id);
+/**reversi/matchを呼ぶ 相手がまだ申し込みを受けていなければNone*/
+async fn request_match(config:&ConfigFile,client:&Client,user_id:&str,accept_only:bool)->Result<Option<MatchResponse>,String>{
+	let mut url=reqwest::Url::parse(config.instance.as_ref()).unwrap();
+	url.set_path("api/reversi/match");
+	let req=MatchRequest{
+		accept_only,
+		i:config.token.clone(),
+		user_id:user_id.to_owned(),
+	};
+	let res=client.post(url).header("Content-Type","application/json").body(serde_json::to_string(&req).unwrap()).send().await;
+	let res=res.and_then(|res|res.error_for_status()).map_err(|e|format!("{:?}",e))?;
+	let b=res.bytes().await.map_err(|e|format!("{:?}",e))?;
+	if b.is_empty(){
+		return Ok(None);
+	}
+	serde_json::from_slice::<Option<MatchResponse>>(&b).map_err(|e|format!("{:?} {:?}",e,str::from_utf8(&b)))
+}
+/**bot自身のユーザー情報*/
+async fn fetch_self(config:&ConfigFile,client:&Client)->Result<MiUser,String>{
+	let mut url=reqwest::Url::parse(config.instance.as_ref()).unwrap();
+	url.set_path("api/i");
+	let req=ShowUserRequest{
+		i:config.token.clone(),
+	};
+	let res=client.post(url).header("Content-Type","application/json").body(serde_json::to_string(&req).unwrap()).send().await;
+	let res=res.and_then(|res|res.error_for_status()).map_err(|e|format!("{:?}",e))?;
+	let b=res.bytes().await.map_err(|e|format!("{:?}",e))?;
+	serde_json::from_slice::<MiUser>(&b).map_err(|e|format!("{:?}",e))
+}
+/**終局してからendedを待つ時間*/
+const ENDED_WAIT:tokio::time::Duration=tokio::time::Duration::from_secs(10);
+/**Noneなら終わらない*/
+async fn sleep_for(wait:Option<tokio::time::Duration>){
+	match wait{
+		Some(wait)=>tokio::time::sleep(wait).await,
+		None=>std::future::pending().await,
+	}
+}
+/**探索していなければ終わらない*/
+async fn search_done(task:Option<&mut SearchTask>)->Result<SearchDone,tokio::task::JoinError>{
+	match task{
+		Some(task)=>task.await,
+		None=>std::future::pending().await,
+	}
+}
+/**読みかけの探索を捨てる*/
+fn abort_search(search:&mut Option<SearchTask>,state:&BotState,game_id:&str){
+	if let Some(task)=search.take(){
+		task.abort();
+		state.engine.cancel(game_id);
+	}
+}
+/**slotは対局が終わるまで持ち続ける*/
+fn spawn_game(con:&Arc<WSStream>,client:&Client,state:&Arc<BotState>,game:GameContext,slot:policy::GameSlot){
+	//対局中のログには全て対局のidと相手が付く
+	let opponent=game.opponent_name().unwrap_or_else(||game.opponent_id().to_owned());
+	let span=tracing::info_span!(parent:None,"game",id=%game.id,opponent=%opponent);
+	let task=join_game(state.config(),con.clone(),client.clone(),state.clone(),game);
+	tokio::runtime::Handle::current().spawn(async move{
+		task.await;
+		drop(slot);
+	}.instrument(span));
+}
+async fn join_game(config:Arc<ConfigFile>,con:Arc<WSStream>,client:Client,state:Arc<BotState>,mut game:GameContext){
+	tracing::info!("join");
 	let mut ws=WSState::new(con.clone()).await.unwrap();
 	let (s,mut r)=tokio::sync::mpsc::channel(2);
+	let surrender=Arc::new(tokio::sync::Notify::new());
+	//手元の盤で終局した時刻
+	let mut game_over_at:Option<tokio::time::Instant>=None;
+	//読んでいる間もイベントを受けられるよう探索は別のタスクで動かす
+	let mut search:Option<SearchTask>=None;
+	state.games.lock().await.insert(game.id.clone(),GameHandle{
+		surrender:surrender.clone(),
+		opponent_id:game.opponent_id().to_owned(),
+	});
+	//コマンドで頼まれた対局なら結果をそのノートに返信する
+	if let Some(note_id)=state.play_requests.lock().await.remove(game.opponent_id()){
+		game.reply_to=Some(note_id);
+	}
+	if let Some(offset)=state.strength_offsets.lock().await.get(game.opponent_id()){
+		game.profile=strength::adjust(&game.profile,*offset);
+	}
 	let mut parms=serde_json::Map::new();
 	parms.insert("gameId".into(), game.id.as_str().into());
 	ws.open_channel(s, MiChannel::ReversiGame,Some(serde_json::Value::Object(parms))).await.unwrap();
-	while let Some(event)=r.recv().await{
+	loop{
+		if search.is_none(){
+			search=game.start_turn(&config,&state);
+		}
+		let ack_wait=game.ack_wait();
+		let ended_wait=game_over_at.map(|t|(t+ENDED_WAIT).saturating_duration_since(tokio::time::Instant::now()));
+		let event=tokio::select!{
+			event=r.recv()=>match event{
+				Some(event)=>event,
+				None=>break,
+			},
+			_=surrender.notified()=>WSChannel{
+				t:"surrender".to_owned(),
+				id:game.id.clone(),
+				body:serde_json::Value::Null,
+			},
+			done=search_done(search.as_mut())=>{
+				search=None;
+				match done{
+					Ok(done)=>{
+						let span=tracing::info_span!("move",ply=done.ply+1);
+						game.play(done,&client,&mut ws,&config).instrument(span).await;
+					},
+					Err(e)=>{
+						tracing::error!("search task failed {}",e);
+						game.surrender(&client,&config).await;
+					},
+				}
+				if game.surrendered{
+					let winner_id=game.judge("surrender");
+					game.finish(&client,&config,&state,"surrender",winner_id).await;
+					break;
+				}
+				continue;
+			},
+			_=sleep_for(ack_wait)=>{
+				game.on_ack_timeout(&client,&mut ws,&config).await;
+				continue;
+			},
+			_=sleep_for(ended_wait)=>{
+				//endedが届かなくても記録は残す
+				tracing::warn!("no ended event, judging locally");
+				let winner_id=game.judge("normal");
+				game.finish(&client,&config,&state,"normal",winner_id).await;
+				break;
+			},
+		};
 		match event.t.as_str(){
 			"updateSettings"=>{
-				println!("updateSettings");
-				println!("{:?}",event);
+				tracing::debug!(body=?event.body,"updateSettings");
 				if let Some(Some(key))=event.body.get("key").map(|k|k.as_str()){
+					let value=event.body.get("value").unwrap_or(&serde_json::Value::Null);
 					if key=="bw"{
-						println!("bw {:?}",event.body.get("value"));
+						tracing::info!(value=?event.body.get("value"),"bw");
+					}else if key=="map"{
+						match serde_json::value::from_value::<Vec<String>>(value.clone()).ok().and_then(|map|MiBoard::from_map(&map)){
+							Some(board)=>{
+								tracing::info!(width=board.width(),height=board.height(),"map");
+								game.board=board;
+							},
+							None=>{
+								//扱えないマップなら断る
+								tracing::warn!("unsupported map {:?}",value);
+								let _=ws.send_channel("cancel".to_string(),Some(serde_json::Value::Object(serde_json::Map::new()))).await;
+							}
+						}
+					}else if key=="timeLimitForEachTurn"{
+						game.clock.set_turn_limit(value.as_u64());
+						tracing::info!(value=?value,"timeLimitForEachTurn");
+					}else if game.rules.update(key,value){
+						tracing::info!(rules=?game.rules,"rules");
 					}else{
 						let _=ws.send_channel("cancel".to_string(),Some(serde_json::Value::Object(serde_json::Map::new()))).await;
 					}
@@ -535,63 +1589,187 @@ async fn join_game(config:Arc<ConfigFile>,con:Arc<WSStream>,client:Client,mut ga
 				}
 			},
 			"canceled"=>{
-				println!("canceled");
+				tracing::info!("canceled");
+				abort_search(&mut search,&state,&game.id);
 				break;
 			},
+			"ended"=>{
+				let winner_id=event.body.get("winnerId").and_then(|v|v.as_str()).map(|id|id.to_owned());
+				let detail=event.body.get("game").and_then(|v|serde_json::value::from_value::<ReversiGameDetail>(v.clone()).map_err(|e|tracing::warn!("ended parse error {:?}",e)).ok());
+				let reason=match detail.as_ref(){
+					Some(detail) if detail.surrendered_user_id.is_some()=>"surrender",
+					Some(detail) if detail.timeout_user_id.is_some()=>"timeout",
+					_=>"normal",
+				};
+				tracing::info!(ply=game.log.len(),winner=?winner_id,reason,"ended");
+				abort_search(&mut search,&state,&game.id);
+				//取りこぼした手があれば最終局面をサーバーに合わせる
+				if let Some(detail)=detail.as_ref(){
+					if !detail.map.is_empty()&&detail.logs.len()>game.log.len(){
+						if let Err(e)=game.apply_detail(detail){
+							tracing::warn!("{}",e);
+						}
+					}
+				}
+				game.finish(&client,&config,&state,reason,winner_id).await;
+				break;
+			},
+			"surrender"=>{
+				//オペレーターからの投了要求
+				abort_search(&mut search,&state,&game.id);
+				game.surrender(&client,&config).await;
+			},
 			"started"=>{
-				println!("started");
-				if let Some(Some(Some(black)))=event.body.get("game").map(|v|v.get("black").map(|k|k.as_u64())){
-					game.user2_is_black=black==2;
-					game.user2_is_active_player=game.user2_is_black;
+				//サーバーの状態を正として盤面を作る 取れなければstartedの内容を使う
+				if !game.resync(&client,&config).await{
+					match event.body.get("game").map(|v|serde_json::value::from_value::<ReversiGameDetail>(v.clone())){
+						Some(Ok(detail))=>if let Err(e)=game.apply_detail(&detail){
+							//対局が始まっているので断れない
+							tracing::warn!("{}",e);
+							game.surrender(&client,&config).await;
+						},
+						e=>tracing::warn!("started parse error {:?}",e),
+					}
 				}
+				tracing::info!(black=game.is_self_black(),turn=?game.turn,"started");
 				//配置する位置を生成したり
-				println!("{:?}",game);
-				game.put_stone(&client,&mut ws,&config).await;
+				tracing::debug!(game=?game,"game state");
 				game.board.debug_dump();
 			},
+			"reconnected"=>{
+				//切断中の手を取りこぼしているかもしれないので取り直す
+				game.resync(&client,&config).await;
+			},
 			"log"=>{
 				if let Some(Some(operation))=event.body.get("operation").map(|v|v.as_str()){
 					if operation=="put"{
 						if let Some(Some(pos))=event.body.get("pos").map(|v|v.as_u64()){
-							let pos=pos as u8;
-							//すでに配置済の場所には置けない
-							if !game.log.contains(&pos){
-								println!("log put {}",pos);
-								game.board.put_stone(pos as u8,!game.is_self_black());
-								game.log.push(pos);
-								game.board.debug_dump();
-								game.user2_is_active_player=game.user2_is_self;
-								game.put_stone_and_loop(&client,&mut ws,&config).await;
-								game.board.debug_dump();
+							let player=event.body.get("player").and_then(|v|v.as_bool());
+							let time=event.body.get("time").and_then(|v|v.as_u64());
+							let id=event.body.get("id").and_then(|v|v.as_str());
+							if !game.apply_log(pos as u16,player,time,id){
+								tracing::warn!(ply=game.log.len(),pos,player=?player,"desync");
+								game.resync(&client,&config).await;
 							}
+							game.board.debug_dump();
 						}
 					}else{
-						println!("{:?}",event)
+						tracing::debug!(event=?event,"unhandled log operation");
 					}
 				}
 			}
 			_=>{
-				println!("{:?}",event)
+				tracing::debug!(event=?event,"unhandled game event");
 			}
 		}
+		if game.surrendered{
+			let winner_id=game.judge("surrender");
+			game.finish(&client,&config,&state,"surrender",winner_id).await;
+			break;
+		}
+		if game.is_game_over()&&game_over_at.is_none(){
+			//勝者や理由はサーバーのendedを正とするので少し待つ
+			game_over_at=Some(tokio::time::Instant::now());
+		}
+	}
+	state.games.lock().await.remove(&game.id);
+	if let Some(task)=search.take(){
+		task.abort();
+	}
+	state.engine.cancel(&game.id);
+	ws.close_channel().await;
+}
+/**標準入力からオペレーターのコマンドを受け付ける 応答はログとは別に標準出力に返す*/
+async fn operator_console(con:Arc<WSStream>,client:Client,state:Arc<BotState>){
+	use tokio::io::AsyncBufReadExt;
+	let mut lines=tokio::io::BufReader::new(tokio::io::stdin()).lines();
+	while let Ok(Some(line))=lines.next_line().await{
+		let mut args=line.split_whitespace();
+		match args.next(){
+			Some("surrender")=>{
+				let count=state.request_surrender(args.next()).await;
+				println!("surrender requested {} game(s)",count);
+			},
+			Some("invite")=>{
+				match args.next(){
+					Some(user_id)=>sparring::invite(&state.config(),&con,&client,&state,user_id).await,
+					None=>println!("usage: invite <userId>"),
+				}
+			},
+			Some("pause")=>{
+				state.paused.store(true,std::sync::atomic::Ordering::SeqCst);
+				println!("paused");
+			},
+			Some("resume")=>{
+				state.paused.store(false,std::sync::atomic::Ordering::SeqCst);
+				println!("resumed");
+			},
+			Some("stats")=>{
+				let stats=state.stats.lock().await;
+				println!("bot rating {:.0}",stats.rating);
+				for player in stats.ranking().iter().filter(|p|args.clone().next().map(|id|id==p.user_id).unwrap_or(true)){
+					println!("{} {} {}",player.user_id,player.name.as_deref().unwrap_or(""),stats::summary(player));
+				}
+			},
+			Some("reload")=>{
+				if let Err(e)=state.reload_config(){
+					println!("reload error {}",e);
+				}
+			},
+			Some(cmd)=>{
+				println!("unknown command {}",cmd);
+			},
+			None=>{},
+		}
+	}
+}
+fn load_config()->Result<ConfigFile,String>{
+	let f=std::fs::File::open("config.json").map_err(|e|format!("{:?}",e))?;
+	serde_json::from_reader(f).map_err(|e|format!("{:?}",e))
+}
+/**テスト用の最小限の設定 extraのキーで足したり上書きしたりする*/
+#[cfg(test)]
+fn test_config(extra:serde_json::Value)->ConfigFile{
+	let mut config=serde_json::json!({
+		"instance":"https://example.com",
+		"token":"",
+		"depth":4,
+		"perfect_search_depth":8,
+	});
+	if let (Some(config),serde_json::Value::Object(extra))=(config.as_object_mut(),extra){
+		config.extend(extra);
 	}
+	serde_json::from_value(config).unwrap()
 }
 fn main() {
-	let config=Arc::new(serde_json::from_reader(std::fs::File::open("config.json").unwrap()).unwrap());
+	let config=Arc::new(load_config().unwrap());
+	logging::init(&config.log);
 	tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap().block_on(async{
 		let client=Client::default();
+		let me=fetch_self(&config,&client).await.unwrap();
+		tracing::info!(user=%me.id,username=%me.username,"login");
+		let state=Arc::new(BotState::new(&config,&client,me.id));
 		let con=new_stream(&config,client.clone()).await.unwrap();
-		check_invites(config,con,client).await;
+		tokio::runtime::Handle::current().spawn(operator_console(con.clone(),client.clone(),state.clone()));
+		if config.sparring.is_some(){
+			tokio::runtime::Handle::current().spawn(sparring::run(con.clone(),client.clone(),state.clone()));
+		}
+		if config.random_match.is_some(){
+			tokio::runtime::Handle::current().spawn(random_match::run(con.clone(),client.clone(),state.clone()));
+		}
+		if config.command.is_some(){
+			tokio::runtime::Handle::current().spawn(command::run(con.clone(),client.clone(),state.clone()));
+		}
+		check_invites(con,client,state).await;
 		std::process::exit(1);
 	});
 }
 async fn new_stream(config:&ConfigFile,client:Client)->Result<Arc<WSStream>,reqwest_websocket::Error>{
-	use reqwest_websocket::RequestBuilderExt;
 	let url=reqwest::Url::parse(config.instance.as_ref());
 	let mut url=match url {
 		Ok(url)=>url,
 		Err(e)=>{
-			eprintln!("{:?}",e);
+			tracing::error!("{:?}",e);
 			panic!("URL parse error");
 		}
 	};
@@ -603,20 +1781,27 @@ async fn new_stream(config:&ConfigFile,client:Client)->Result<Arc<WSStream>,reqw
 	url.set_path("streaming");
 	let query=format!("i={}",config.token.as_str());
 	url.set_query(Some(&query));
+	let host=url.host_str().unwrap_or("").to_owned();
+	let websocket=connect_websocket(&client,url.clone()).await?;
+	let ws=Arc::new(WSStream::new(websocket,url,client));
+	let ws0=ws.clone();
+	//受信と再接続のログには接続先が付く
+	let span=tracing::info_span!(parent:None,"connection",host=%host);
+	tokio::runtime::Handle::current().spawn(async move{
+		let _=ws0.load().await;
+	}.instrument(span));
+	tracing::info!(host=%host,"websocket connected");
+	Ok(ws)
+}
+async fn connect_websocket(client:&Client,url:reqwest::Url)->Result<reqwest_websocket::WebSocket,reqwest_websocket::Error>{
+	use reqwest_websocket::RequestBuilderExt;
 	// create a GET request, upgrade it and send it.
 	let response = client
 		.get(url)
 		.upgrade() // <-- prepares the websocket upgrade.
 		.send()
 		.await?;
-	let websocket = response.into_websocket().await?;
-	let ws=Arc::new(WSStream::new(websocket));
-	let ws0=ws.clone();
-	tokio::runtime::Handle::current().spawn(async move{
-		let _=ws0.load().await;
-	});
-	println!("=============Open Connection===============");
-	Ok(ws)
+	response.into_websocket().await
 }
 struct WSState{
 	stream:Option<Arc<WSStream>>,
@@ -632,7 +1817,7 @@ impl WSState{
 	async fn close_channel(&mut self){
 		if let Some(id)=self.now_stream.take(){
 			if let Err(e)=self.stream.as_ref().unwrap().close_channel(id).await{
-				println!("close stream error {:?}",e);
+				tracing::warn!(channel=id,"close channel error {:?}",e);
 			}
 		}
 		if let Some(stream)=self.stream.take(){
@@ -641,7 +1826,6 @@ impl WSState{
 		}
 	}
 	async fn send_channel(&mut self,cmd_type:String,parms:Option<serde_json::Value>)->Result<(),reqwest_websocket::Error>{
-		println!("=============Send Channel===============");
 		let mut websocket=self.stream.as_ref().unwrap().send.lock().await;
 		let mut map=serde_json::Map::new();
 		map.insert("type".to_owned(), "ch".into());
@@ -653,18 +1837,17 @@ impl WSState{
 		}
 		map.insert("body".into(), body.into());
 		let s=serde_json::to_string(&map).unwrap();
-		println!("{}",s);
+		tracing::debug!(channel=self.now_stream,"send {}",s);
 		websocket.send(reqwest_websocket::Message::Text(s.into())).await?;
 		Ok(())
 	}
 	async fn open_channel(&mut self,sender:tokio::sync::mpsc::Sender<WSChannel>,ch:MiChannel,parms:Option<serde_json::Value>)->Result<(),reqwest_websocket::Error>{
 		let sender=sender.clone();
-		println!("=============Open Stream===============");
 		let new_id=self.stream.as_ref().unwrap().open(move|res: WSChannel|{
 			let sender=sender.clone();
 			let f:BoxFuture<'static,()>=Box::pin(async move{
 				if let Err(e)=sender.send(res).await{
-					eprintln!("{:?}",e);
+					tracing::debug!("channel event dropped {:?}",e);
 				}
 			});
 			f
@@ -676,15 +1859,18 @@ impl WSState{
 		Ok(())
 	}
 }
+#[derive(Clone,Copy,Debug)]
 pub enum MiChannel{
 	ReversiGame,
 	Reversi,
+	Main,
 }
 impl MiChannel{
 	pub fn id(&self)->&'static str{
 		match self {
 			MiChannel::ReversiGame => "reversiGame",
 			MiChannel::Reversi => "reversi",
+			MiChannel::Main => "main",
 		}
 	}
 }
@@ -694,15 +1880,42 @@ impl <F> From<F> for WSChannelListener where F:FnMut(WSChannel)->futures_util::f
 		Self(Box::new(value))
 	}
 }
+/**再接続時に同じidでconnectし直すためチャンネルの種類とパラメータも保持する*/
+struct WSChannelEntry{
+	listener:WSChannelListener,
+	channel:MiChannel,
+	parms:Option<serde_json::Value>,
+}
+impl WSChannelEntry{
+	fn connect_message(&self,id:u32)->String{
+		let mut map=serde_json::Map::new();
+		map.insert("type".to_owned(), "connect".into());
+		let mut body=serde_json::Map::new();
+		body.insert("channel".into(), self.channel.id().into());
+		body.insert("id".into(), id.to_string().into());
+		if let Some(parms)=self.parms.as_ref(){
+			body.insert("params".into(), parms.clone());
+		}
+		map.insert("body".into(), body.into());
+		serde_json::to_string(&map).unwrap()
+	}
+}
+type WSSink=SplitSink<reqwest_websocket::WebSocket, reqwest_websocket::Message>;
+const RECONNECT_BACKOFF_MIN:tokio::time::Duration=tokio::time::Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX:tokio::time::Duration=tokio::time::Duration::from_secs(60);
 struct WSStream{
-	channel_listener:Arc<Mutex<HashMap<u32,WSChannelListener>>>,
+	channel_listener:Arc<Mutex<HashMap<u32,WSChannelEntry>>>,
 	last_id:AtomicU32,
-	send: Arc<Mutex<SplitSink<reqwest_websocket::WebSocket, reqwest_websocket::Message>>>,
+	send: Arc<Mutex<WSSink>>,
 	recv: Mutex<Option<SplitStream<reqwest_websocket::WebSocket>>>,
 	exit: Arc<AtomicBool>,
+	/**切断検知時に読み込み側へ再接続を促す*/
+	broken: Arc<tokio::sync::Notify>,
+	url:reqwest::Url,
+	client:Client,
 }
 impl WSStream{
-	fn new(websocket:reqwest_websocket::WebSocket)->Self{
+	fn new(websocket:reqwest_websocket::WebSocket,url:reqwest::Url,client:Client)->Self{
 		let (send,recv)=websocket.split();
 		Self{
 			channel_listener:Arc::new(Mutex::new(HashMap::new())),
@@ -710,37 +1923,97 @@ impl WSStream{
 			send:Arc::new(Mutex::new(send)),
 			recv:Mutex::new(Some(recv)),
 			exit:Arc::new(AtomicBool::new(false)),
+			broken:Arc::new(tokio::sync::Notify::new()),
+			url,
+			client,
 		}
 	}
 	async fn open(&self,listener:impl Into<WSChannelListener>,channel:MiChannel,parms:Option<serde_json::Value>)->Result<u32,reqwest_websocket::Error>{
 		let mut websocket=self.send.lock().await;
 		let id=self.last_id.fetch_add(1,std::sync::atomic::Ordering::SeqCst);
-		println!("open channel... {}",id);
+		tracing::debug!(channel=id,kind=channel.id(),"open channel");
 		let mut channel_listener=self.channel_listener.lock().await;
-		channel_listener.insert(id,listener.into());
-		let mut map=serde_json::Map::new();
-		map.insert("type".to_owned(), "connect".into());
-		let mut body=serde_json::Map::new();
-		body.insert("channel".into(), channel.id().into());
-		body.insert("id".into(), id.to_string().into());
-		if let Some(parms)=parms{
-			body.insert("params".into(), parms);
-		}
-		map.insert("body".into(), body.into());
-		websocket.send(reqwest_websocket::Message::Text(serde_json::to_string(&map).unwrap().into())).await?;
-		println!("opend channel {}",id);
+		let entry=WSChannelEntry{
+			listener:listener.into(),
+			channel,
+			parms,
+		};
+		let msg=entry.connect_message(id);
+		channel_listener.insert(id,entry);
+		websocket.send(reqwest_websocket::Message::Text(msg)).await?;
+		tracing::debug!(channel=id,"opened channel");
 		Ok(id)
 	}
+	/**新しいwebsocketに張り替えて生きているチャンネルを全てconnectし直す*/
+	async fn reconnect(client:&Client,url:&reqwest::Url,sender:&Mutex<WSSink>,channel_listener:&Mutex<HashMap<u32,WSChannelEntry>>)->Result<SplitStream<reqwest_websocket::WebSocket>,reqwest_websocket::Error>{
+		tracing::info!("reconnecting websocket");
+		let websocket=connect_websocket(client,url.clone()).await?;
+		let (send,recv)=websocket.split();
+		let mut websocket=sender.lock().await;
+		*websocket=send;
+		let mut r=channel_listener.lock().await;
+		for (id,entry) in r.iter(){
+			tracing::debug!(channel=id,kind=entry.channel.id(),"resubscribe channel");
+			websocket.send(reqwest_websocket::Message::Text(entry.connect_message(*id))).await?;
+		}
+		drop(websocket);
+		//購読し直したことを各チャンネルに通知する
+		for (id,entry) in r.iter_mut(){
+			entry.listener.0(WSChannel { t: "reconnected".to_owned(), id: id.to_string(), body: serde_json::Value::Null }).await;
+		}
+		tracing::info!(channels=r.len(),"reconnected");
+		Ok(recv)
+	}
 	async fn close_channel(&self,id:u32)->Result<u32,reqwest_websocket::Error>{
-		println!("close channel... {}",id);
+		tracing::debug!(channel=id,"close channel");
+		//送れなくても再接続時に繋ぎ直さないよう先に消す
+		self.channel_listener.lock().await.remove(&id);
 		let mut websocket=self.send.lock().await;
 		let q=format!("{{\"type\":\"disconnect\",\"body\":{{\"id\":\"{}\"}}}}",id);
 		websocket.send(reqwest_websocket::Message::Text(q.into())).await?;
-		let mut channel_listener=self.channel_listener.lock().await;
-		channel_listener.remove(&id);
-		println!("closed channel {}",id);
+		tracing::debug!(channel=id,"closed channel");
 		Ok(id)
 	}
+	/**切断されるまで受信したイベントを各チャンネルに配る*/
+	async fn dispatch(websocket:&mut SplitStream<reqwest_websocket::WebSocket>,channel_listener:&Mutex<HashMap<u32,WSChannelEntry>>,broken:&tokio::sync::Notify){
+		loop{
+			let message=tokio::select!{
+				message=websocket.try_next()=>message,
+				_=broken.notified()=>return,
+			};
+			let message=match message{
+				Ok(Some(message))=>message,
+				Ok(None)=>return,
+				Err(e)=>{
+					tracing::warn!("websocket error {:?}",e);
+					return;
+				}
+			};
+			match message {
+				reqwest_websocket::Message::Text(text) =>{
+					if let Ok(Some(channel))=serde_json::from_str::<WSResult>(text.as_str()).map(|res|{
+						if res.t.as_str()=="channel"{
+							serde_json::value::from_value::<WSChannel>(res.body).ok()
+						}else{
+							None
+						}
+					}){
+						if let Ok(id)=u32::from_str_radix(channel.id.as_str(),10){
+							let mut r=channel_listener.lock().await;
+							if let Some(entry)=r.get_mut(&id){
+								entry.listener.0(channel).await;
+							}else{
+								tracing::debug!(channel=id,"event for unknown channel");
+							}
+						}
+					}else{
+						tracing::debug!("unhandled message {}",text);
+					}
+				},
+				_=>{}
+			}
+		}
+	}
 	async fn load(&self){
 		let websocket=self.recv.lock().await.take();
 		if websocket.is_none(){
@@ -748,63 +2021,229 @@ impl WSStream{
 		}
 		let mut websocket=websocket.unwrap();
 		let channel_listener=self.channel_listener.clone();
-		let channel_listener0=self.channel_listener.clone();
 		let sender=self.send.clone();
+		let sender0=self.send.clone();
+		let exit=self.exit.clone();
 		let exit0=self.exit.clone();
+		let broken=self.broken.clone();
+		let broken0=self.broken.clone();
+		let url=self.url.clone();
+		let client=self.client.clone();
+		let span=tracing::Span::current();
 		std::thread::spawn(move||{
 			let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
 			let handle=rt.spawn(async move{
-				while let Ok(Some(message)) = websocket.try_next().await {
-					match message {
-						reqwest_websocket::Message::Text(text) =>{
-							if let Ok(Some(channel))=serde_json::from_str::<WSResult>(text.as_str()).map(|res|{
-								if res.t.as_str()=="channel"{
-									serde_json::value::from_value::<WSChannel>(res.body).ok()
-								}else{
-									None
-								}
-							}){
-								if let Ok(id)=u32::from_str_radix(channel.id.as_str(),10){
-									let mut r=channel_listener.lock().await;
-									if let Some(handle)=r.get_mut(&id){
-										handle.0(channel).await;
-									}else{
-										println!("unknown channel event {}",id);
-									}
-								}
-							}else{
-								println!("parse error {}",text);
+				//再接続するたびに増える
+				let mut generation=0u32;
+				loop{
+					Self::dispatch(&mut websocket,&channel_listener,&broken).instrument(tracing::info_span!("socket",generation)).await;
+					tracing::warn!("websocket closed");
+					let mut backoff=RECONNECT_BACKOFF_MIN;
+					websocket=loop{
+						if exit.load(std::sync::atomic::Ordering::Relaxed){
+							return;
+						}
+						tokio::time::sleep(backoff).await;
+						match Self::reconnect(&client,&url,&sender,&channel_listener).await{
+							Ok(websocket)=>{
+								//張り替える前に届いた切断の通知で新しい接続を切らないよう捨てる
+								let _=broken.notified().now_or_never();
+								generation+=1;
+								break websocket;
+							},
+							Err(e)=>{
+								tracing::warn!(backoff=?backoff,"reconnect error {:?}",e);
+								backoff=(backoff*2).min(RECONNECT_BACKOFF_MAX);
 							}
-						},
-						_=>{}
-					}
+						}
+					};
 				}
-				println!("close websocket");
-			});
+			}.instrument(span.clone()));
 			rt.block_on(async{
 				while !exit0.load(std::sync::atomic::Ordering::Relaxed){
-					let mut websocket=sender.lock().await;
+					let mut websocket=sender0.lock().await;
 					if let Err(e)=websocket.send(reqwest_websocket::Message::Text("h".into())).await{
-						println!("ping error {:?}",e);
-						let mut r=channel_listener0.lock().await;
-						for (id,handle) in r.iter_mut(){
-							handle.0(WSChannel { t: "error".to_owned(), id: id.to_string(), body: serde_json::Value::Null }).await;
-						}
+						tracing::warn!("ping error {:?}",e);
+						//読み込み側に切断を伝えて再接続させる dispatchが待っていなくても取りこぼさないようpermitを残す
+						broken0.notify_one();
 					}else{
-						println!("ping ok");
+						tracing::trace!("ping ok");
 					}
 					drop(websocket);
 					tokio::time::sleep(tokio::time::Duration::from_millis(60*1000)).await;
 				}
-			});
+			}.instrument(span));
 			handle.abort();
 		});
 	}
 	async fn close_connection(&self){
-		println!("close connection...");
+		tracing::info!("close connection");
 		self.exit.store(true,std::sync::atomic::Ordering::Relaxed);
+		self.broken.notify_one();
 		let mut websocket=self.send.lock().await;
 		let res=websocket.close().await;
-		println!("closed connection {:?}",res);
+		tracing::info!("closed connection {:?}",res);
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	/**botが黒のuser1で始まった対局*/
+	fn game(map:&[&str],rules:GameRules)->GameContext{
+		let config=test_config(serde_json::json!({}));
+		let res=MatchResponse{
+			id:"game".to_owned(),
+			user1_id:"bot".to_owned(),
+			user2_id:"user".to_owned(),
+			user1:None,
+			user2:None,
+		};
+		let mut game=GameContext::from_match(res,"bot",None,&config);
+		let detail=ReversiGameDetail{
+			id:"game".to_owned(),
+			user1_id:"bot".to_owned(),
+			user2_id:"user".to_owned(),
+			black:Some(1),
+			map:map.iter().map(|s|s.to_string()).collect(),
+			logs:vec![],
+			is_started:true,
+			time_limit_for_each_turn:None,
+			surrendered_user_id:None,
+			timeout_user_id:None,
+			rules,
+		};
+		game.apply_detail(&detail).unwrap();
+		game
+	}
+	const LAST_MOVE:[&str;8]=["-wbbbbbb","bbbbbbbb","bbbbbbbb","bbbbbbbb","bbbbbbbb","bbbbbbbb","bbbbbbbb","bbbbbbbb"];
+	#[test]
+	fn game_is_over_after_the_last_move(){
+		let mut game=game(&LAST_MOVE,GameRules::default());
+		assert!(game.is_self_black());
+		assert!(game.is_self_turn());
+		assert!(!game.is_game_over());
+		assert!(game.apply_log(0,Some(true),None,None));
+		assert_eq!(game.turn,Turn::Over);
+		assert!(game.is_game_over());
+		assert_eq!(game.judge("normal").as_deref(),Some("bot"));
+		//投了なら石の数によらず相手の勝ち
+		assert_eq!(game.judge("surrender").as_deref(),Some("user"));
+	}
+	#[test]
+	fn llotheo_winner_has_fewer_stones(){
+		let rules=GameRules{
+			is_llotheo:true,
+			..Default::default()
+		};
+		let mut game=game(&LAST_MOVE,rules);
+		assert!(game.apply_log(0,Some(true),None,None));
+		assert!(game.is_game_over());
+		assert_eq!(game.judge("normal").as_deref(),Some("user"));
+	}
+	const INITIAL:[&str;8]=["--------","--------","--------","---wb---","---bw---","--------","--------","--------"];
+	/**f5を送って確認待ちにした対局*/
+	fn sent_f5()->GameContext{
+		let mut game=game(&INITIAL,GameRules::default());
+		game.put_own_move(37,"abc".to_owned());
+		assert_eq!(game.turn,Turn::White);
+		game
+	}
+	#[test]
+	fn log_with_the_sent_id_acks_the_move(){
+		let mut game=sent_f5();
+		assert!(game.apply_log(37,Some(true),None,Some("abc")));
+		assert!(game.pending.is_none());
+		assert_eq!(game.acked_id.as_deref(),Some("abc"));
+		assert_eq!(game.synced,1);
+		//送り直しで同じlogがもう一度届いても打たない
+		assert!(game.apply_log(37,Some(true),None,Some("abc")));
+		assert_eq!(game.synced,1);
+		assert_eq!(game.log.len(),1);
+		assert_eq!(game.turn,Turn::White);
+	}
+	#[test]
+	fn log_with_the_sent_id_at_another_position_is_a_desync(){
+		let mut game=sent_f5();
+		assert!(!game.apply_log(19,Some(true),None,Some("abc")));
+		assert!(game.pending.is_some());
+		assert_eq!(game.synced,0);
+	}
+	#[test]
+	fn log_without_id_at_the_sent_position_acks_the_move(){
+		let mut game=sent_f5();
+		assert!(game.apply_log(37,None,None,None));
+		assert!(game.pending.is_none());
+		assert_eq!(game.acked_id.as_deref(),Some("abc"));
+		assert_eq!(game.synced,1);
+	}
+	#[test]
+	fn opponent_log_implicitly_acks_the_move(){
+		let mut game=sent_f5();
+		//白のd6
+		assert!(game.apply_log(43,Some(false),None,None));
+		assert!(game.pending.is_none());
+		assert_eq!(game.acked_id.as_deref(),Some("abc"));
+		assert_eq!(game.synced,2);
+		assert_eq!(game.log.len(),2);
+		assert_eq!(game.turn,Turn::Black);
+	}
+	#[test]
+	fn own_log_with_another_id_is_rejected(){
+		let mut game=sent_f5();
+		assert!(!game.apply_log(37,Some(true),None,Some("xyz")));
+		assert!(game.pending.is_some());
+		assert!(game.acked_id.is_none());
+		assert_eq!(game.synced,0);
+	}
+	#[test]
+	fn ack_timeout_resends_then_resyncs(){
+		let mut game=sent_f5();
+		for attempts in 1..MAX_SEND_ATTEMPTS{
+			game.pending.as_mut().unwrap().attempts=attempts;
+			assert_eq!(game.ack_timed_out(),Some(AckTimeout::Resend));
+		}
+		game.pending.as_mut().unwrap().attempts=MAX_SEND_ATTEMPTS;
+		assert_eq!(game.ack_timed_out(),Some(AckTimeout::Resync{
+			pos:37,
+			index:0,
+		}));
+		//すぐ次を試さないよう待ち時間を延ばしている
+		assert!(game.ack_wait().unwrap()>tokio::time::Duration::from_millis(ACK_TIMEOUT_MILLIS/2));
+		game.pending=None;
+		assert_eq!(game.ack_timed_out(),None);
+	}
+	#[tokio::test]
+	async fn bot_moves_twice_when_the_opponent_must_pass(){
+		let stats_file=std::env::temp_dir().join(format!("dekunobou_stats_pass_{}.json",std::process::id()));
+		let config=Arc::new(test_config(serde_json::json!({
+			"engine":["local"],
+			"stats_file":stats_file.to_str().unwrap(),
+		})));
+		let state=Arc::new(BotState::new(&config,&Client::new(),"bot".to_owned()));
+		//白はどこにも置けない 黒がaに置いた後も白は置けずfにもう一度黒が置ける
+		let mut game=game(&["-wbbw-"],GameRules::default());
+		assert_eq!(game.turn,Turn::Black);
+		game.put_own_move(0,"first".to_owned());
+		assert_eq!(game.turn,Turn::Black);
+		//送った手のlogが届くまで次を読まない
+		assert!(game.start_turn(&config,&state).is_none());
+		assert!(game.apply_log(0,Some(true),None,Some("first")));
+		let task=game.start_turn(&config,&state).unwrap();
+		task.abort();
+		game.put_own_move(5,"second".to_owned());
+		assert_eq!(game.turn,Turn::Over);
+		assert!(game.is_game_over());
+	}
+	#[test]
+	fn full_even_board_is_a_draw(){
+		let map=["bbbbbbbb","bbbbbbbb","bbbbbbbb","bbbbbbbb","wwwwwwww","wwwwwwww","wwwwwwww","wwwwwwww"];
+		let mut game=game(&map,GameRules::default());
+		assert!(game.is_game_over());
+		assert_eq!(game.judge("normal"),None);
+		//始まっていなければ終局ではない
+		game.started_at=None;
+		assert!(!game.is_game_over());
 	}
 }
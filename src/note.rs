@@ -0,0 +1,115 @@
+//! 終局した対局の結果をノートに投稿する
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::{kifu, ConfigFile, GameContext, MiBoard, WALL};
+
+#[derive(Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct NoteConfig{
+	/**public,home,followers,specified*/
+	pub visibility:String,
+	/**{opponent} {result} {winner} {black} {white} {board} {url} {reason}を置き換える*/
+	pub template:String,
+	/**対局相手に返信する specifiedなら相手にだけ見える*/
+	pub reply:bool,
+}
+impl Default for NoteConfig{
+	fn default()->Self{
+		Self{
+			visibility:"home".to_owned(),
+			template:"{opponent} との対局が終わりました {result}\n⚫{black} - ⚪{white}\n{board}\n{url}".to_owned(),
+			reply:false,
+		}
+	}
+}
+#[derive(Serialize,Deserialize,Debug)]
+struct NoteCreateRequest{
+	i:String,
+	text:String,
+	visibility:String,
+	#[serde(rename = "replyId",skip_serializing_if = "Option::is_none")]
+	reply_id:Option<String>,
+	#[serde(rename = "visibleUserIds",skip_serializing_if = "Vec::is_empty")]
+	visible_user_ids:Vec<String>,
+}
+/**絵文字で盤面を描く*/
+pub fn emoji_board(board:&MiBoard)->String{
+	let mut s=String::new();
+	for (i,c) in board.cells().enumerate(){
+		s.push_str(match c{
+			1=>"⚫",
+			2=>"⚪",
+			WALL=>"⬛",
+			_=>"🟩",
+		});
+		if i%board.width()==board.width()-1{
+			s.push('\n');
+		}
+	}
+	s.trim_end().to_owned()
+}
+fn render(template:&str,game:&GameContext,record:&kifu::GameRecord)->String{
+	let result=match record.result.winner_id.as_deref(){
+		None=>"引き分け",
+		Some(id) if id==record.self_id=>"勝ち",
+		Some(_)=>"負け",
+	};
+	let winner=match record.result.winner_id.as_deref(){
+		None=>"なし",
+		Some(id) if (id==record.self_id)==record.self_is_black=>"黒",
+		Some(_)=>"白",
+	};
+	let reason=match record.result.reason.as_str(){
+		"surrender"=>"投了",
+		"timeout"=>"時間切れ",
+		_=>"",
+	};
+	template
+		.replace("{opponent}",record.opponent.as_deref().unwrap_or(&record.opponent_id))
+		.replace("{result}",result)
+		.replace("{winner}",winner)
+		.replace("{black}",&record.result.black.to_string())
+		.replace("{white}",&record.result.white.to_string())
+		.replace("{board}",&emoji_board(&game.board))
+		.replace("{url}",&record.url)
+		.replace("{reason}",reason)
+}
+/**notes/createで投稿する 失敗しても対局には影響しないのでログだけ出す*/
+pub async fn post(config:&ConfigFile,client:&Client,text:String,reply_id:Option<String>,visibility:&str,visible_user_ids:Vec<String>){
+	let mut url=reqwest::Url::parse(config.instance.as_ref()).unwrap();
+	url.set_path("api/notes/create");
+	let req=NoteCreateRequest{
+		i:config.token.clone(),
+		text,
+		visibility:visibility.to_owned(),
+		reply_id,
+		visible_user_ids,
+	};
+	let res=client.post(url).header("Content-Type","application/json").body(serde_json::to_string(&req).unwrap()).send().await;
+	if let Err(e)=res.and_then(|res|res.error_for_status()){
+		tracing::error!("notes/create error {:?}",e);
+	}
+}
+pub async fn post_result(config:&ConfigFile,client:&Client,game:&GameContext,record:&kifu::GameRecord){
+	let note=match config.note.as_ref(){
+		Some(note)=>note,
+		None=>return,
+	};
+	let mut text=render(&note.template,game,record);
+	let mut reply_id=None;
+	let mut visible_user_ids=vec![];
+	if note.reply{
+		//対局を頼まれたノートがあればそこに返信 なければ相手へのメンションにする
+		reply_id=game.reply_to.clone();
+		if reply_id.is_none(){
+			if let Some(opponent)=record.opponent.as_ref(){
+				text=format!("{} {}",opponent,text);
+			}
+		}
+		if note.visibility=="specified"{
+			visible_user_ids.push(record.opponent_id.clone());
+		}
+	}
+	post(config,client,text,reply_id,&note.visibility,visible_user_ids).await;
+}
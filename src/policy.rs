@@ -0,0 +1,189 @@
+//! 招待を受けるかどうかの判定
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{ConfigFile, MiUser};
+
+#[derive(Serialize,Deserialize,Debug)]
+pub struct RateLimit{
+	/**per_secs秒の間に受ける招待の数*/
+	pub count:usize,
+	pub per_secs:u64,
+}
+#[derive(Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct InvitePolicy{
+	/**空でなければここにあるユーザーidかallowed_hostsのホストからの招待だけ受ける*/
+	pub allowed_users:Vec<String>,
+	pub allowed_hosts:Vec<String>,
+	pub blocked_users:Vec<String>,
+	pub blocked_hosts:Vec<String>,
+	/**botからの招待を受けるか*/
+	pub accept_bots:bool,
+	/**同時に進める対局数の上限*/
+	pub max_concurrent_games:Option<usize>,
+	/**ユーザーごとの招待の受け付け頻度*/
+	pub rate_limit:Option<RateLimit>,
+}
+impl Default for InvitePolicy{
+	fn default()->Self{
+		Self{
+			allowed_users:vec![],
+			allowed_hosts:vec![],
+			blocked_users:vec![],
+			blocked_hosts:vec![],
+			accept_bots:true,
+			max_concurrent_games:None,
+			rate_limit:None,
+		}
+	}
+}
+/**ユーザーごとに受けた招待の時刻*/
+pub struct InviteHistory(Mutex<HashMap<String,VecDeque<Instant>>>);
+impl InviteHistory{
+	pub fn new()->Self{
+		Self(Mutex::new(HashMap::new()))
+	}
+	/**制限内なら今回の招待を記録してtrue*/
+	async fn try_record(&self,user_id:&str,limit:&RateLimit)->bool{
+		let mut history=self.0.lock().await;
+		let now=Instant::now();
+		let window=Duration::from_secs(limit.per_secs);
+		let times=history.entry(user_id.to_owned()).or_default();
+		while times.front().map(|t|now.duration_since(*t)>=window).unwrap_or(false){
+			times.pop_front();
+		}
+		if times.len()>=limit.count{
+			return false;
+		}
+		times.push_back(now);
+		true
+	}
+}
+/**進行中と開始処理中の対局数*/
+pub struct GameSlots(Arc<AtomicUsize>);
+/**対局1つ分の枠 対局が終わるか開始に失敗して捨てられたら空く*/
+pub struct GameSlot(Arc<AtomicUsize>);
+impl Drop for GameSlot{
+	fn drop(&mut self){
+		self.0.fetch_sub(1,Ordering::SeqCst);
+	}
+}
+impl GameSlots{
+	pub fn new()->Self{
+		Self(Arc::new(AtomicUsize::new(0)))
+	}
+	/**max_concurrent_gamesを超えないように枠を取る 同時に呼ばれても超えない*/
+	pub fn reserve(&self,max:Option<usize>)->Result<GameSlot,String>{
+		let max=max.unwrap_or(usize::MAX);
+		self.0.fetch_update(Ordering::SeqCst,Ordering::SeqCst,|n|if n<max{
+			Some(n+1)
+		}else{
+			None
+		}).map_err(|n|format!("too many games {}/{}",n,max))?;
+		Ok(GameSlot(self.0.clone()))
+	}
+	/**既に始まってしまった対局の枠 上限を超えても数える*/
+	pub fn force(&self)->GameSlot{
+		self.0.fetch_add(1,Ordering::SeqCst);
+		GameSlot(self.0.clone())
+	}
+}
+/**招待を受けられなければその理由 対局数の上限はGameSlotsで見る*/
+pub async fn check(config:&ConfigFile,history:&InviteHistory,user:&MiUser)->Result<(),String>{
+	let policy=&config.policy;
+	let host=user.host_or_instance(&config.instance);
+	let host=host.as_deref().unwrap_or("");
+	if policy.blocked_users.contains(&user.id){
+		return Err("blocked user".to_owned());
+	}
+	if policy.blocked_hosts.iter().any(|h|h==host){
+		return Err(format!("blocked host {}",host));
+	}
+	if (!policy.allowed_users.is_empty()||!policy.allowed_hosts.is_empty())&&!policy.allowed_users.contains(&user.id)&&!policy.allowed_hosts.iter().any(|h|h==host){
+		return Err("not in allow list".to_owned());
+	}
+	if user.is_bot&&!policy.accept_bots{
+		return Err("bot".to_owned());
+	}
+	if let Some(limit)=policy.rate_limit.as_ref(){
+		if !history.try_record(&user.id,limit).await{
+			return Err(format!("rate limit {} per {}s",limit.count,limit.per_secs));
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	fn config_with(policy:serde_json::Value)->ConfigFile{
+		crate::test_config(serde_json::json!({
+			"policy":policy,
+		}))
+	}
+	fn user(id:&str,host:Option<&str>,is_bot:bool)->MiUser{
+		MiUser{
+			id:id.to_owned(),
+			name:None,
+			username:id.to_owned(),
+			host:host.map(|h|h.to_owned()),
+			is_bot,
+			is_cat:false,
+		}
+	}
+	#[tokio::test]
+	async fn block_and_allow_lists(){
+		let history=InviteHistory::new();
+		let config=config_with(serde_json::json!({
+			"blocked_users":["blocked"],
+			"blocked_hosts":["bad.example"],
+		}));
+		assert!(check(&config,&history,&user("someone",None,false)).await.is_ok());
+		assert!(check(&config,&history,&user("blocked",None,false)).await.is_err());
+		assert!(check(&config,&history,&user("someone",Some("bad.example"),false)).await.is_err());
+		//ローカルのユーザーはインスタンスのホストで判定する
+		let config=config_with(serde_json::json!({
+			"allowed_users":["friend"],
+			"allowed_hosts":["example.com"],
+		}));
+		assert!(check(&config,&history,&user("someone",None,false)).await.is_ok());
+		assert!(check(&config,&history,&user("friend",Some("other.example"),false)).await.is_ok());
+		assert!(check(&config,&history,&user("someone",Some("other.example"),false)).await.is_err());
+	}
+	#[tokio::test]
+	async fn bots_and_rate_limit(){
+		let history=InviteHistory::new();
+		let config=config_with(serde_json::json!({
+			"accept_bots":false,
+			"rate_limit":{"count":2,"per_secs":3600},
+		}));
+		assert!(check(&config,&history,&user("bot",None,true)).await.is_err());
+		assert!(check(&config,&history,&user("a",None,false)).await.is_ok());
+		assert!(check(&config,&history,&user("a",None,false)).await.is_ok());
+		assert!(check(&config,&history,&user("a",None,false)).await.is_err());
+		//ユーザーごとに数える
+		assert!(check(&config,&history,&user("b",None,false)).await.is_ok());
+	}
+	#[test]
+	fn slots_never_exceed_the_cap(){
+		let slots=GameSlots::new();
+		let a=slots.reserve(Some(2)).unwrap();
+		let b=slots.reserve(Some(2)).unwrap();
+		assert!(slots.reserve(Some(2)).is_err());
+		drop(a);
+		let c=slots.reserve(Some(2)).unwrap();
+		//始まってしまった対局は上限を超えても数える
+		let d=slots.force();
+		assert_eq!(slots.0.load(Ordering::SeqCst),3);
+		drop((b,c,d));
+		assert_eq!(slots.0.load(Ordering::SeqCst),0);
+		assert!(slots.reserve(None).is_ok());
+	}
+}
@@ -0,0 +1,119 @@
+//! dekunobouのFFI呼び出しを専用のスレッドで動かす
+//!
+//! 探索中はスレッドを占有するのでtokioのワーカーでは呼ばない
+//! 待ち行列は対局ごとに分けて順番に取り出し 1つの対局が他の対局を待たせ続けないようにする
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::DekunobouBoard;
+
+/**待たせておける探索の数*/
+const MAX_QUEUED:usize=64;
+
+struct Job{
+	board:std::ffi::CString,
+	is_white:bool,
+	depth:u32,
+	perfect_search_depth:u32,
+	reply:tokio::sync::oneshot::Sender<u32>,
+}
+#[derive(Default)]
+struct Queue{
+	/**対局idごとの待ち*/
+	jobs:HashMap<String,VecDeque<Job>>,
+	/**次に取り出す対局idの順番*/
+	order:VecDeque<String>,
+	len:usize,
+}
+impl Queue{
+	/**先頭の対局から1つ取り出してその対局を最後に回す*/
+	fn pop(&mut self)->Option<Job>{
+		while let Some(game_id)=self.order.pop_front(){
+			let jobs=match self.jobs.get_mut(&game_id){
+				Some(jobs)=>jobs,
+				None=>continue,
+			};
+			let job=jobs.pop_front();
+			if jobs.is_empty(){
+				self.jobs.remove(&game_id);
+			}else{
+				self.order.push_back(game_id);
+			}
+			if let Some(job)=job{
+				self.len-=1;
+				return Some(job);
+			}
+		}
+		None
+	}
+}
+pub struct EnginePool{
+	shared:Arc<(Mutex<Queue>,Condvar)>,
+}
+impl EnginePool{
+	pub fn new(threads:usize)->Self{
+		let shared=Arc::new((Mutex::new(Queue::default()),Condvar::new()));
+		for i in 0..threads.max(1){
+			let shared=shared.clone();
+			std::thread::Builder::new().name(format!("dekunobou-{}",i)).spawn(move||worker(&shared)).unwrap();
+		}
+		Self{
+			shared,
+		}
+	}
+	/**探索を待ち行列に入れて結果を待つ 待ち行列が一杯か取り消されたらNone*/
+	pub async fn search(&self,game_id:&str,board:&DekunobouBoard,is_black:bool,depth:u32,perfect_search_depth:u32)->Option<u32>{
+		let (reply,r)=tokio::sync::oneshot::channel();
+		let board=std::ffi::CString::new(board.0.as_str()).ok()?;
+		{
+			let (queue,cond)=&*self.shared;
+			let mut queue=queue.lock().unwrap();
+			if queue.len>=MAX_QUEUED{
+				tracing::warn!(game=game_id,"engine queue full");
+				return None;
+			}
+			if !queue.jobs.contains_key(game_id){
+				queue.order.push_back(game_id.to_owned());
+			}
+			queue.jobs.entry(game_id.to_owned()).or_default().push_back(Job{
+				board,
+				is_white:!is_black,
+				depth,
+				perfect_search_depth,
+				reply,
+			});
+			queue.len+=1;
+			cond.notify_one();
+		}
+		r.await.ok()
+	}
+	/**対局の待っている探索を取り消す 探索中のものは結果を捨てる*/
+	pub fn cancel(&self,game_id:&str){
+		let mut queue=self.shared.0.lock().unwrap();
+		if let Some(jobs)=queue.jobs.remove(game_id){
+			queue.len-=jobs.len();
+			tracing::debug!(game=game_id,jobs=jobs.len(),"engine jobs canceled");
+		}
+		queue.order.retain(|id|id!=game_id);
+	}
+}
+fn worker(shared:&(Mutex<Queue>,Condvar)){
+	let (queue,cond)=shared;
+	loop{
+		let job={
+			let mut queue=queue.lock().unwrap();
+			loop{
+				if let Some(job)=queue.pop(){
+					break job;
+				}
+				queue=cond.wait(queue).unwrap();
+			}
+		};
+		//待っている間に時間切れや終局で要らなくなった
+		if job.reply.is_closed(){
+			continue;
+		}
+		let pos=unsafe { dekunobou::dekunobou(job.board.as_ptr(),job.is_white,job.depth,job.perfect_search_depth) };
+		let _=job.reply.send(pos);
+	}
+}
@@ -0,0 +1,132 @@
+//! ランダムマッチの待ち行列に並ぶ
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::{BotState, ConfigFile, GameContext, MatchResponse, WSStream};
+
+#[derive(Serialize,Deserialize,Debug)]
+pub struct RandomMatchConfig{
+	/**変則ルールの対局を避ける*/
+	#[serde(default)]
+	pub no_irregular_rules:bool,
+	/**待ち行列に並び直す間隔(秒) 行列は15秒で消えるのでそれより短くする*/
+	#[serde(default="default_poll_secs")]
+	pub poll_secs:u64,
+}
+fn default_poll_secs()->u64{
+	5
+}
+#[derive(Serialize,Deserialize,Debug)]
+struct RandomMatchRequest{
+	i:String,
+	/**nullならランダムマッチ*/
+	#[serde(rename = "userId")]
+	user_id:Option<String>,
+	#[serde(rename = "noIrregularRules")]
+	no_irregular_rules:bool,
+}
+#[derive(Serialize,Deserialize,Debug)]
+struct CancelMatchRequest{
+	i:String,
+	#[serde(rename = "userId")]
+	user_id:Option<String>,
+}
+/**待ち行列に並ぶ 相手が見つかればその対局*/
+async fn request(config:&ConfigFile,client:&Client,random:&RandomMatchConfig)->Result<Option<MatchResponse>,String>{
+	let mut url=reqwest::Url::parse(config.instance.as_ref()).unwrap();
+	url.set_path("api/reversi/match");
+	let req=RandomMatchRequest{
+		i:config.token.clone(),
+		user_id:None,
+		no_irregular_rules:random.no_irregular_rules,
+	};
+	let res=client.post(url).header("Content-Type","application/json").body(serde_json::to_string(&req).unwrap()).send().await;
+	let res=res.and_then(|res|res.error_for_status()).map_err(|e|format!("{:?}",e))?;
+	let b=res.bytes().await.map_err(|e|format!("{:?}",e))?;
+	if b.is_empty(){
+		return Ok(None);
+	}
+	serde_json::from_slice::<Option<MatchResponse>>(&b).map_err(|e|format!("{:?}",e))
+}
+/**待ち行列から抜ける*/
+async fn cancel(config:&ConfigFile,client:&Client){
+	let mut url=reqwest::Url::parse(config.instance.as_ref()).unwrap();
+	url.set_path("api/reversi/cancel-match");
+	let req=CancelMatchRequest{
+		i:config.token.clone(),
+		user_id:None,
+	};
+	let res=client.post(url).header("Content-Type","application/json").body(serde_json::to_string(&req).unwrap()).send().await;
+	if let Err(e)=res.and_then(|res|res.error_for_status()){
+		tracing::error!("cancel-match error {:?}",e);
+	}
+}
+/**設定から消されている間に様子を見る間隔(秒)*/
+const IDLE_SECS:u64=60;
+pub async fn run(con:Arc<WSStream>,client:Client,state:Arc<BotState>){
+	let mut backoff=tokio::time::Duration::ZERO;
+	loop{
+		//reload configで変えた間隔や上限もすぐ使う
+		let config=state.config();
+		let random=config.random_match.as_ref();
+		let poll=tokio::time::Duration::from_secs(random.map(|random|random.poll_secs.max(1)).unwrap_or(IDLE_SECS));
+		//ランダムマッチの対局が終わるまで並ばない
+		let random_game=state.random_game.lock().await.clone();
+		if let Some(id)=random_game{
+			if state.games.lock().await.contains_key(&id){
+				tokio::time::sleep(poll).await;
+				continue;
+			}
+			*state.random_game.lock().await=None;
+		}
+		//設定から消された時も止められている時と同じく行列から抜ける
+		let paused=state.paused.load(Ordering::SeqCst)||random.is_none();
+		//並んでいる間は枠を取っておき 行列から相手が決まった時にmatchedで使う
+		let mut slot=state.random_slot.lock().await;
+		if slot.is_none()&&!paused{
+			*slot=state.game_slots.reserve(config.policy.max_concurrent_games).ok();
+		}
+		if paused||slot.is_none(){
+			//招待で埋まっているか止められているので間隔を空けて様子を見る
+			*slot=None;
+			drop(slot);
+			if state.in_random_queue.swap(false,Ordering::SeqCst){
+				cancel(&config,&client).await;
+			}
+			backoff=(backoff*2).max(poll).min(tokio::time::Duration::from_secs(300));
+			tokio::time::sleep(backoff).await;
+			continue;
+		}
+		drop(slot);
+		backoff=tokio::time::Duration::ZERO;
+		let random=match random{
+			Some(random)=>random,
+			None=>continue,
+		};
+		state.in_random_queue.store(true,Ordering::SeqCst);
+		match request(&config,&client,random).await{
+			Ok(Some(res))=>{
+				state.in_random_queue.store(false,Ordering::SeqCst);
+				tracing::info!(game=%res.id,"random match");
+				*state.random_game.lock().await=Some(res.id.clone());
+				let slot=state.random_slot.lock().await.take().unwrap_or_else(||state.game_slots.force());
+				let game=GameContext::from_match(res,&state.self_id,None,&config);
+				crate::spawn_game(&con,&client,&state,game,slot);
+			},
+			Ok(None)=>{
+				//問い合わせ中に前の並びでmatchedが届いて枠を使われたら 並び直した分は枠がないので抜ける
+				if !state.in_random_queue.load(Ordering::SeqCst){
+					tracing::debug!("matched while requeueing, leaving the queue");
+					cancel(&config,&client).await;
+				}
+			},
+			Err(e)=>{
+				tracing::warn!("random match error {}",e);
+			}
+		}
+		tokio::time::sleep(poll).await;
+	}
+}
@@ -0,0 +1,528 @@
+//! 手元での探索
+//!
+//! 8x8で通常の挟み方ならbitboardで反復深化しながら読み 残りが少なければ最後まで読み切る
+//! それ以外の変則ルールや大きなマップはマスを1つずつ見て浅く読む
+//! どちらも時間切れか取り消されたら最後に読み終えた深さの手を返す
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::bitboard::{self, Bitboard};
+use crate::{GameRules, MiBoard};
+
+/**勝敗が確定した局面の評価値の基準*/
+const WIN_SCORE:i32=10000;
+/**時間切れで打つ手を探す時間(ミリ秒)*/
+const FALLBACK_MILLIS:u64=300;
+/**時間を確かめる間隔(局面数) 2の累乗*/
+const CHECK_NODES:u64=1024;
+/**マスごとの重み 隅は良くその隣は悪い*/
+const WEIGHTS:[i32;64]=[
+	100,-20,10,5,5,10,-20,100,
+	-20,-50,-2,-2,-2,-2,-50,-20,
+	10,-2,-1,-1,-1,-1,-2,10,
+	5,-2,-1,-1,-1,-1,-2,5,
+	5,-2,-1,-1,-1,-1,-2,5,
+	10,-2,-1,-1,-1,-1,-2,10,
+	-20,-50,-2,-2,-2,-2,-50,-20,
+	100,-20,10,5,5,10,-20,100,
+];
+/**置ける場所の数の差の重み*/
+const MOBILITY_WEIGHT:i32=5;
+
+/**探索の結果*/
+pub struct SearchOutcome{
+	pub pos:u16,
+	/**手番側から見た評価値*/
+	pub eval:i32,
+	/**読み筋*/
+	pub pv:Vec<u16>,
+	/**読み終えた深さ*/
+	pub depth:u32,
+}
+
+/**手番側から見た石数差 ロセオなら少ない方が良い*/
+fn evaluate(board:&MiBoard,is_black:bool,rules:&GameRules)->i32{
+	let (black,white)=board.count();
+	let diff=if is_black{
+		black as i32-white as i32
+	}else{
+		white as i32-black as i32
+	};
+	if rules.is_llotheo{
+		-diff
+	}else{
+		diff
+	}
+}
+/**探索を打ち切る条件*/
+struct Stop{
+	deadline:Option<Instant>,
+	/**対局が終わった時などに外から立てる*/
+	cancel:Option<Arc<AtomicBool>>,
+	nodes:u64,
+	aborted:bool,
+}
+impl Stop{
+	fn new(deadline:Option<Instant>,cancel:Option<Arc<AtomicBool>>)->Self{
+		Self{
+			deadline,
+			cancel,
+			nodes:0,
+			aborted:false,
+		}
+	}
+	fn time_up(&mut self)->bool{
+		self.nodes+=1;
+		if self.nodes&(CHECK_NODES-1)==0{
+			if let Some(deadline)=self.deadline{
+				if Instant::now()>=deadline{
+					self.aborted=true;
+				}
+			}
+			if let Some(cancel)=self.cancel.as_ref(){
+				if cancel.load(Ordering::Relaxed){
+					self.aborted=true;
+				}
+			}
+		}
+		self.aborted
+	}
+}
+/**マスを1つずつ見る盤での探索*/
+struct GridSearch<'a>{
+	rules:&'a GameRules,
+	stop:Stop,
+}
+impl GridSearch<'_>{
+	fn negamax(&mut self,board:&MiBoard,is_black:bool,depth:u32,mut alpha:i32,beta:i32,passed:bool)->i32{
+		if self.stop.time_up(){
+			return 0;
+		}
+		let list=board.legal_move_list(is_black,self.rules);
+		if list.is_empty(){
+			if passed{
+				//両者とも置けないので終局
+				let score=evaluate(board,is_black,self.rules);
+				return score.signum()*WIN_SCORE+score;
+			}
+			return -self.negamax(board,!is_black,depth,-beta,-alpha,true);
+		}
+		if depth==0{
+			return evaluate(board,is_black,self.rules);
+		}
+		for pos in list{
+			let mut next=board.clone();
+			next.put_stone_silent(pos,is_black,self.rules);
+			let score=-self.negamax(&next,!is_black,depth-1,-beta,-alpha,false);
+			if self.stop.aborted{
+				return 0;
+			}
+			if score>alpha{
+				alpha=score;
+				if alpha>=beta{
+					break;
+				}
+			}
+		}
+		alpha
+	}
+	/**1つの深さで全ての手を読む 前の深さの最善手から読む 時間切れならNone*/
+	fn root(&mut self,board:&MiBoard,is_black:bool,depth:u32,hint:u16)->Option<(u16,i32)>{
+		let mut list=board.legal_move_list(is_black,self.rules);
+		list.sort_by_key(|pos|*pos!=hint);
+		let mut best=None;
+		let mut alpha=-WIN_SCORE*2;
+		for pos in list{
+			let mut next=board.clone();
+			next.put_stone_silent(pos,is_black,self.rules);
+			let score=-self.negamax(&next,!is_black,depth-1,-WIN_SCORE*2,-alpha,false);
+			if self.stop.aborted{
+				return None;
+			}
+			if best.is_none()||score>alpha{
+				alpha=score;
+				best=Some(pos);
+			}
+		}
+		best.map(|pos|(pos,alpha))
+	}
+}
+/**マスを1つずつ見る盤で1手ずつ深く読む*/
+fn grid_best_move(board:&MiBoard,is_black:bool,rules:&GameRules,depth:u32,stop:Stop)->Option<SearchOutcome>{
+	//どこにでも置けるルールや大きいマップは分岐が多いので浅く読む
+	let max_depth=if rules.can_put_everywhere||board.len()>150{
+		3
+	}else if board.len()>64{
+		4
+	}else{
+		5
+	};
+	let first=*board.legal_move_list(is_black,rules).first()?;
+	let mut search=GridSearch{
+		rules,
+		stop,
+	};
+	let mut best=SearchOutcome{
+		pos:first,
+		eval:0,
+		pv:vec![first],
+		depth:0,
+	};
+	for d in 1..=depth.clamp(1,max_depth){
+		match search.root(board,is_black,d,best.pos){
+			Some((pos,eval))=>{
+				best=SearchOutcome{
+					pos,
+					eval,
+					pv:vec![pos],
+					depth:d,
+				};
+			},
+			None=>break,
+		}
+	}
+	Some(best)
+}
+/**置換表の値の種類*/
+#[derive(Clone,Copy,PartialEq)]
+enum Bound{
+	Exact,
+	Lower,
+	Upper,
+}
+struct Entry{
+	depth:u32,
+	score:i32,
+	bound:Bound,
+	/**この局面で一番良かった手 次の探索で最初に読む*/
+	best:Option<u16>,
+}
+/**bitboardでの探索 置換表を持つ*/
+struct BitSearch{
+	is_llotheo:bool,
+	/**最後まで読み切っているか 読み切りなら葉は石数差で評価する*/
+	exact:bool,
+	table:HashMap<u64,Entry>,
+	stop:Stop,
+}
+impl BitSearch{
+	fn new(is_llotheo:bool,stop:Stop)->Self{
+		Self{
+			is_llotheo,
+			exact:false,
+			table:HashMap::new(),
+			stop,
+		}
+	}
+	/**手番側から見た石数差*/
+	fn disc_diff(&self,b:&Bitboard,is_black:bool)->i32{
+		let (black,white)=b.count();
+		let diff=if is_black{
+			black as i32-white as i32
+		}else{
+			white as i32-black as i32
+		};
+		if self.is_llotheo{
+			-diff
+		}else{
+			diff
+		}
+	}
+	/**マスの重みと置ける場所の数 ロセオは石数差と置ける場所の数*/
+	fn evaluate(&self,b:&Bitboard,is_black:bool)->i32{
+		let (p,o)=b.sides(is_black);
+		let mobility=b.legal_moves(is_black).count_ones() as i32-b.legal_moves(!is_black).count_ones() as i32;
+		if self.is_llotheo{
+			return self.disc_diff(b,is_black)*10+mobility*MOBILITY_WEIGHT;
+		}
+		let pattern=bitboard::bits(p).map(|pos|WEIGHTS[pos as usize]).sum::<i32>()-bitboard::bits(o).map(|pos|WEIGHTS[pos as usize]).sum::<i32>();
+		pattern+mobility*MOBILITY_WEIGHT
+	}
+	/**置換表の手を先に 残りはマスの重みの順*/
+	fn order(&self,moves:u64,hint:Option<u16>)->Vec<u16>{
+		let mut list=bitboard::bits(moves).collect::<Vec<_>>();
+		list.sort_by_key(|pos|(Some(*pos)!=hint,-WEIGHTS[*pos as usize]));
+		list
+	}
+	fn negamax(&mut self,b:&Bitboard,is_black:bool,depth:u32,mut alpha:i32,beta:i32,passed:bool)->i32{
+		if self.stop.time_up(){
+			return 0;
+		}
+		let moves=b.legal_moves(is_black);
+		if moves==0{
+			if passed{
+				//両者とも置けないので終局
+				let score=self.disc_diff(b,is_black);
+				return score.signum()*WIN_SCORE+score;
+			}
+			return -self.negamax(b,!is_black,depth,-beta,-alpha,true);
+		}
+		if depth==0{
+			return if self.exact{
+				self.disc_diff(b,is_black)
+			}else{
+				self.evaluate(b,is_black)
+			};
+		}
+		let key=b.hash(is_black);
+		let mut hint=None;
+		if let Some(e)=self.table.get(&key){
+			hint=e.best;
+			if e.depth>=depth{
+				match e.bound{
+					Bound::Exact=>return e.score,
+					Bound::Lower if e.score>=beta=>return e.score,
+					Bound::Upper if e.score<=alpha=>return e.score,
+					_=>{},
+				}
+			}
+		}
+		let alpha0=alpha;
+		let mut best=-WIN_SCORE*2;
+		let mut best_move=None;
+		for pos in self.order(moves,hint){
+			let mut next=*b;
+			next.play(pos,is_black);
+			let score=-self.negamax(&next,!is_black,depth-1,-beta,-alpha,false);
+			if self.stop.aborted{
+				return 0;
+			}
+			if score>best{
+				best=score;
+				best_move=Some(pos);
+			}
+			if score>alpha{
+				alpha=score;
+				if alpha>=beta{
+					break;
+				}
+			}
+		}
+		let bound=if best<=alpha0{
+			Bound::Upper
+		}else if best>=beta{
+			Bound::Lower
+		}else{
+			Bound::Exact
+		};
+		self.table.insert(key,Entry{
+			depth,
+			score:best,
+			bound,
+			best:best_move,
+		});
+		best
+	}
+	/**1つの深さで全ての手を読む 時間切れならNone*/
+	fn root(&mut self,b:&Bitboard,is_black:bool,depth:u32,hint:Option<u16>)->Option<(u16,i32)>{
+		let mut best=None;
+		let mut alpha=-WIN_SCORE*2;
+		for pos in self.order(b.legal_moves(is_black),hint){
+			let mut next=*b;
+			next.play(pos,is_black);
+			let score=-self.negamax(&next,!is_black,depth-1,-WIN_SCORE*2,-alpha,false);
+			if self.stop.aborted{
+				return None;
+			}
+			if best.is_none()||score>alpha{
+				alpha=score;
+				best=Some(pos);
+			}
+		}
+		best.map(|pos|(pos,alpha))
+	}
+	/**置換表をたどって読み筋を作る*/
+	fn pv(&self,b:&Bitboard,is_black:bool,first:u16,depth:u32)->Vec<u16>{
+		let mut pv=vec![first];
+		let mut b=*b;
+		let mut is_black=is_black;
+		b.play(first,is_black);
+		is_black = !is_black;
+		while (pv.len() as u32)<depth{
+			if b.legal_moves(is_black)==0{
+				if b.legal_moves(!is_black)==0{
+					break;
+				}
+				is_black = !is_black;
+			}
+			let pos=match self.table.get(&b.hash(is_black)).and_then(|e|e.best){
+				Some(pos) if b.legal_moves(is_black)>>pos&1==1=>pos,
+				_=>break,
+			};
+			pv.push(pos);
+			b.play(pos,is_black);
+			is_black = !is_black;
+		}
+		pv
+	}
+}
+/**bitboardで反復深化 残りの空きがendgame_empties以下なら読み切る*/
+fn bitboard_best_move(b:&Bitboard,is_black:bool,is_llotheo:bool,depth:u32,endgame_empties:u32,stop:Stop)->Option<SearchOutcome>{
+	let moves=b.legal_moves(is_black);
+	let first=bitboard::bits(moves).next()?;
+	let mut search=BitSearch::new(is_llotheo,stop);
+	let empties=b.empty().count_ones();
+	let mut best=SearchOutcome{
+		pos:first,
+		eval:0,
+		pv:vec![first],
+		depth:0,
+	};
+	if empties<=endgame_empties{
+		search.exact=true;
+		if let Some((pos,eval))=search.root(b,is_black,empties,None){
+			return Some(SearchOutcome{
+				pos,
+				eval,
+				pv:search.pv(b,is_black,pos,empties),
+				depth:empties,
+			});
+		}
+		//読み切れなかったので普通に読む
+		tracing::warn!(empties,"endgame search timed out");
+		search.exact=false;
+		search.stop.aborted=false;
+		search.stop.deadline=search.stop.deadline.map(|d|d.max(Instant::now()+Duration::from_millis(FALLBACK_MILLIS)));
+		search.table.clear();
+	}
+	for d in 1..=depth.max(1).min(empties){
+		match search.root(b,is_black,d,Some(best.pos)){
+			Some((pos,eval))=>{
+				best=SearchOutcome{
+					pos,
+					eval,
+					pv:search.pv(b,is_black,pos,d),
+					depth:d,
+				};
+			},
+			None=>break,
+		}
+	}
+	Some(best)
+}
+/**time_millisまでにdepthまで読む cancelが立ったらそこで打ち切る 置ける場所がなければNone*/
+pub fn best_move(board:&MiBoard,is_black:bool,rules:&GameRules,depth:u32,endgame_empties:u32,time_millis:Option<u64>,cancel:Option<Arc<AtomicBool>>)->Option<SearchOutcome>{
+	let stop=Stop::new(time_millis.map(|t|Instant::now()+Duration::from_millis(t)),cancel);
+	match board.bitboard_for(rules){
+		Some(b)=>bitboard_best_move(&b,is_black,rules.is_llotheo,depth,endgame_empties,stop),
+		None=>grid_best_move(board,is_black,rules,depth,stop),
+	}
+}
+/**時間切れの時にすぐ打つ手 置ける場所がなければNone*/
+pub fn fallback_move(board:&MiBoard,is_black:bool,rules:&GameRules)->Option<u16>{
+	best_move(board,is_black,rules,6,10,Some(FALLBACK_MILLIS),None).map(|o|o.pos)
+}
+
+#[cfg(test)]
+mod tests{
+	use rand::{Rng, SeedableRng};
+
+	use super::*;
+
+	/**枝刈りせずに最後まで読んだ評価値*/
+	fn solve(b:&Bitboard,is_black:bool,is_llotheo:bool,passed:bool)->i32{
+		let moves=b.legal_moves(is_black);
+		if moves==0{
+			if passed{
+				let (black,white)=b.count();
+				let diff=black as i32-white as i32;
+				let score=if is_black!=is_llotheo{
+					diff
+				}else{
+					-diff
+				};
+				return score.signum()*WIN_SCORE+score;
+			}
+			return -solve(b,!is_black,is_llotheo,true);
+		}
+		bitboard::bits(moves).map(|pos|{
+			let mut next=*b;
+			next.play(pos,is_black);
+			-solve(&next,!is_black,is_llotheo,false)
+		}).max().unwrap()
+	}
+	/**空きがempties個になるまで適当に打った局面 手番側が置けなければNone*/
+	fn endgame(seed:u64,empties:u32)->Option<(Bitboard,bool)>{
+		let mut rng=rand::rngs::StdRng::seed_from_u64(seed);
+		let mut b=MiBoard::new().to_bitboard().unwrap();
+		let mut is_black=true;
+		while b.empty().count_ones()>empties{
+			let moves=bitboard::bits(b.legal_moves(is_black)).collect::<Vec<_>>();
+			if moves.is_empty(){
+				if b.legal_moves(!is_black)==0{
+					return None;
+				}
+				is_black = !is_black;
+				continue;
+			}
+			b.play(moves[rng.gen_range(0..moves.len())],is_black);
+			is_black = !is_black;
+		}
+		if b.legal_moves(is_black)==0{
+			return None;
+		}
+		Some((b,is_black))
+	}
+	fn grid_board(b:&Bitboard)->MiBoard{
+		MiBoard::Grid{
+			width:8,
+			height:8,
+			cells:(0..64).map(|i|b.cell(i)).collect(),
+		}
+	}
+	#[test]
+	fn endgame_solver_matches_brute_force(){
+		let mut solved=0;
+		for seed in 0..40{
+			let Some((b,is_black))=endgame(seed,8) else{
+				continue;
+			};
+			for is_llotheo in [false,true]{
+				let expected=solve(&b,is_black,is_llotheo,false);
+				let outcome=bitboard_best_move(&b,is_black,is_llotheo,60,10,Stop::new(None,None)).unwrap();
+				assert_eq!(outcome.eval,expected,"seed {}",seed);
+				assert_eq!(outcome.depth,8);
+				//選んだ手もその評価値になる
+				let mut next=b;
+				next.play(outcome.pos,is_black);
+				assert_eq!(-solve(&next,!is_black,is_llotheo,false),expected,"seed {}",seed);
+			}
+			solved+=1;
+		}
+		assert!(solved>20);
+	}
+	#[test]
+	fn grid_search_matches_brute_force_near_the_end(){
+		let rules=GameRules::default();
+		for seed in 0..40{
+			let Some((b,is_black))=endgame(seed,4) else{
+				continue;
+			};
+			let outcome=grid_best_move(&grid_board(&b),is_black,&rules,60,Stop::new(None,None)).unwrap();
+			assert_eq!(outcome.eval,solve(&b,is_black,false,false),"seed {}",seed);
+		}
+	}
+	#[test]
+	fn expired_search_still_returns_a_legal_move(){
+		let rules=GameRules::default();
+		let (b,is_black)=endgame(3,30).unwrap();
+		let legal=b.legal_moves(is_black);
+		let deadline=Some(Instant::now()-Duration::from_millis(1));
+		let outcome=grid_best_move(&grid_board(&b),is_black,&rules,60,Stop::new(deadline,None)).unwrap();
+		assert!(legal>>outcome.pos&1==1);
+		let outcome=bitboard_best_move(&b,is_black,false,60,0,Stop::new(deadline,None)).unwrap();
+		assert!(legal>>outcome.pos&1==1);
+	}
+	#[test]
+	fn canceled_search_stops(){
+		let rules=GameRules::default();
+		let board=MiBoard::new();
+		let cancel=Arc::new(AtomicBool::new(true));
+		let started=Instant::now();
+		let outcome=best_move(&board,true,&rules,60,0,None,Some(cancel)).unwrap();
+		assert!(started.elapsed()<Duration::from_secs(1));
+		assert!(board.legal_move_list(true,&rules).contains(&outcome.pos));
+	}
+}
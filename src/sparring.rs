@@ -0,0 +1,74 @@
+//! こちらから対局を申し込む(定期的なbot同士の練習対局など)
+use std::sync::Arc;
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::{BotState, ConfigFile, GameContext, WSStream};
+
+#[derive(Serialize,Deserialize,Debug)]
+pub struct SparringConfig{
+	/**申し込む相手のユーザーid*/
+	pub users:Vec<String>,
+	/**申し込む間隔(秒)*/
+	pub interval_secs:u64,
+	/**最初に申し込む時刻(UTCの"HH:MM") 未指定なら起動直後*/
+	pub at:Option<String>,
+}
+impl SparringConfig{
+	/**次のatまでの秒数*/
+	fn first_wait(&self)->u64{
+		let at=match self.at.as_ref().and_then(|at|at.split_once(':')){
+			Some((h,m))=>match (h.parse::<u64>(),m.parse::<u64>()){
+				(Ok(h),Ok(m)) if h<24&&m<60=>h*3600+m*60,
+				_=>{
+					tracing::warn!("invalid sparring time {:?}",self.at);
+					return 0;
+				}
+			},
+			None=>return 0,
+		};
+		let now=crate::kifu::now_millis()/1000%86400;
+		(at+86400-now)%86400
+	}
+}
+/**userに対局を申し込む 相手から既に申し込まれていればそのまま対局を始める*/
+pub async fn invite(config:&Arc<ConfigFile>,con:&Arc<WSStream>,client:&Client,state:&Arc<BotState>,user_id:&str){
+	tracing::info!(user=user_id,"invite");
+	match crate::request_match(config,client,user_id,false).await{
+		Ok(Some(res))=>{
+			let game=GameContext::from_match(res,&state.self_id,None,config);
+			crate::spawn_game(con,client,state,game,state.game_slots.force());
+		},
+		Ok(None)=>{
+			//相手が受けるとreversiチャンネルにmatchedが届く
+			tracing::info!(user=user_id,"invited, waiting for accept");
+			let mut invites=state.outgoing_invites.lock().await;
+			invites.retain(|_,t|t.elapsed()<crate::INVITE_TTL);
+			invites.insert(user_id.to_owned(),std::time::Instant::now());
+		},
+		Err(e)=>{
+			tracing::error!(user=user_id,"invite error {}",e);
+		}
+	}
+}
+pub async fn run(con:Arc<WSStream>,client:Client,state:Arc<BotState>){
+	let first_wait=state.config().sparring.as_ref().map(|sparring|sparring.first_wait()).unwrap_or(0);
+	tokio::time::sleep(tokio::time::Duration::from_secs(first_wait)).await;
+	loop{
+		//reload configで変えた相手や間隔もすぐ使う 設定から消されたら申し込まずに様子を見る
+		let config=state.config();
+		let interval=match config.sparring.as_ref(){
+			Some(sparring)=>{
+				if !state.paused.load(std::sync::atomic::Ordering::SeqCst){
+					for user_id in sparring.users.iter(){
+						invite(&config,&con,&client,&state,user_id).await;
+					}
+				}
+				sparring.interval_secs
+			},
+			None=>0,
+		};
+		tokio::time::sleep(tokio::time::Duration::from_secs(interval.max(60))).await;
+	}
+}
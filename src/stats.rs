@@ -0,0 +1,189 @@
+//! 対局相手ごとの成績とレーティング
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::kifu;
+
+/**初期レーティング*/
+const INITIAL_RATING:f64=1500.0;
+/**Eloの更新幅*/
+const K_FACTOR:f64=32.0;
+
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct PlayerStats{
+	pub user_id:String,
+	pub host:Option<String>,
+	/**@username@host 最後に対局した時のもの*/
+	pub name:Option<String>,
+	pub wins:u32,
+	pub losses:u32,
+	pub draws:u32,
+	/**botから見た石差の合計*/
+	pub disc_diff:i64,
+	/**相手のレーティング*/
+	pub rating:f64,
+}
+impl PlayerStats{
+	fn new(user_id:&str,host:Option<String>)->Self{
+		Self{
+			user_id:user_id.to_owned(),
+			host,
+			name:None,
+			wins:0,
+			losses:0,
+			draws:0,
+			disc_diff:0,
+			rating:INITIAL_RATING,
+		}
+	}
+	pub fn games(&self)->u32{
+		self.wins+self.losses+self.draws
+	}
+}
+/**ファイルに保存する成績表 勝ち負けはbotから見たもの*/
+#[derive(Serialize,Deserialize,Debug)]
+pub struct StatsStore{
+	/**botのレーティング*/
+	pub rating:f64,
+	/**"id@host"ごとの成績*/
+	pub players:HashMap<String,PlayerStats>,
+	#[serde(skip)]
+	path:String,
+}
+fn key(user_id:&str,host:Option<&str>)->String{
+	format!("{}@{}",user_id,host.unwrap_or(""))
+}
+/**Eloの期待勝率*/
+fn expected(rating:f64,opponent:f64)->f64{
+	1.0/(1.0+10f64.powf((opponent-rating)/400.0))
+}
+impl StatsStore{
+	/**pathから読む なければ空の成績表*/
+	pub fn load(path:&str)->Self{
+		let store=std::fs::read_to_string(path).ok().and_then(|s|serde_json::from_str::<StatsStore>(&s).map_err(|e|tracing::warn!(path,"stats parse error {:?}",e)).ok());
+		let mut store=store.unwrap_or(Self{
+			rating:INITIAL_RATING,
+			players:HashMap::new(),
+			path:String::new(),
+		});
+		store.path=path.to_owned();
+		store
+	}
+	fn save(&self)->std::io::Result<()>{
+		if let Some(dir)=std::path::Path::new(&self.path).parent(){
+			std::fs::create_dir_all(dir)?;
+		}
+		let json=serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+		std::fs::write(&self.path,json)
+	}
+	/**ホストが分からずに記録した成績もidで探す*/
+	pub fn get(&self,user_id:&str,host:Option<&str>)->Option<&PlayerStats>{
+		self.players.get(&key(user_id,host)).or_else(||self.players.values().find(|p|p.user_id==user_id))
+	}
+	/**対局数の多い順*/
+	pub fn ranking(&self)->Vec<&PlayerStats>{
+		let mut players=self.players.values().collect::<Vec<_>>();
+		players.sort_by(|a,b|b.games().cmp(&a.games()).then(b.rating.total_cmp(&a.rating)));
+		players
+	}
+	/**終局した対局を反映して保存する*/
+	pub fn record(&mut self,record:&kifu::GameRecord,host:Option<String>){
+		let k=key(&record.opponent_id,host.as_deref());
+		let player=self.players.entry(k).or_insert_with(||PlayerStats::new(&record.opponent_id,host));
+		if record.opponent.is_some(){
+			player.name=record.opponent.clone();
+		}
+		let (self_count,opponent_count)=if record.self_is_black{
+			(record.result.black,record.result.white)
+		}else{
+			(record.result.white,record.result.black)
+		};
+		player.disc_diff+=self_count as i64-opponent_count as i64;
+		let score=match record.result.winner_id.as_deref(){
+			None=>{
+				player.draws+=1;
+				0.5
+			},
+			Some(id) if id==record.self_id=>{
+				player.wins+=1;
+				1.0
+			},
+			Some(_)=>{
+				player.losses+=1;
+				0.0
+			},
+		};
+		let e=expected(self.rating,player.rating);
+		self.rating+=K_FACTOR*(score-e);
+		player.rating-=K_FACTOR*(score-e);
+		tracing::info!(user=%record.opponent_id,wins=player.wins,losses=player.losses,draws=player.draws,rating=player.rating.round(),bot_rating=self.rating.round(),"stats updated");
+		if let Err(e)=self.save(){
+			tracing::error!(path=%self.path,"stats save error {:?}",e);
+		}
+	}
+}
+/**成績を1行で*/
+pub fn summary(stats:&PlayerStats)->String{
+	format!("{}勝{}敗{}分 石差{:+} レーティング{:.0}",stats.wins,stats.losses,stats.draws,stats.disc_diff,stats.rating)
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	fn record(winner_id:Option<&str>,black:u32,white:u32)->kifu::GameRecord{
+		let mut record=kifu::tests::record(&[]);
+		record.result=kifu::GameResult{
+			black,
+			white,
+			winner_id:winner_id.map(|id|id.to_owned()),
+			reason:"normal".to_owned(),
+		};
+		record
+	}
+	/**一時ファイルに保存する成績表*/
+	fn store(name:&str)->StatsStore{
+		let path=std::env::temp_dir().join(format!("dekunobou_stats_{}_{}.json",name,std::process::id()));
+		let _=std::fs::remove_file(&path);
+		StatsStore::load(path.to_str().unwrap())
+	}
+	#[test]
+	fn expected_score(){
+		assert_eq!(expected(1500.0,1500.0),0.5);
+		//400点差で10倍
+		assert!((expected(1900.0,1500.0)-10.0/11.0).abs()<1e-9);
+		assert!((expected(1500.0,1900.0)-1.0/11.0).abs()<1e-9);
+	}
+	#[test]
+	fn win_between_equal_ratings_moves_half_the_k_factor(){
+		let mut store=store("win");
+		store.record(&record(Some("bot"),40,24),Some("example.com".to_owned()));
+		assert_eq!(store.rating,INITIAL_RATING+K_FACTOR/2.0);
+		let player=store.get("user",Some("example.com")).unwrap();
+		assert_eq!(player.rating,INITIAL_RATING-K_FACTOR/2.0);
+		assert_eq!((player.wins,player.losses,player.draws),(1,0,0));
+		assert_eq!(player.disc_diff,16);
+		assert_eq!(player.name.as_deref(),Some("@user@example.com"));
+		//保存したものを読み直せる
+		let reloaded=StatsStore::load(&store.path);
+		assert_eq!(reloaded.rating,store.rating);
+		let _=std::fs::remove_file(&store.path);
+	}
+	#[test]
+	fn loss_and_draw(){
+		let mut store=store("loss");
+		store.record(&record(Some("user"),20,44),None);
+		store.record(&record(None,32,32),None);
+		let player=store.get("user",None).unwrap();
+		assert_eq!((player.wins,player.losses,player.draws),(0,1,1));
+		assert_eq!(player.disc_diff,-24);
+		//負けの後の引き分けはbotのレーティングが上がる
+		assert!(store.rating<INITIAL_RATING);
+		assert!(store.rating>INITIAL_RATING-K_FACTOR/2.0);
+		assert_eq!(store.rating+player.rating,INITIAL_RATING*2.0);
+		//ホストが分からなくてもidで探せる
+		assert!(store.get("user",Some("other.example")).is_some());
+		let _=std::fs::remove_file(&store.path);
+	}
+}
@@ -0,0 +1,83 @@
+//! 対局相手ごとの強さの設定
+use std::collections::HashMap;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{ConfigFile, MiUser};
+
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct StrengthProfile{
+	pub depth:u32,
+	pub perfect_search_depth:u32,
+	/**最善手以外をランダムに選ぶ確率(0.0-1.0)*/
+	#[serde(default)]
+	pub handicap:f64,
+}
+#[derive(Default,Serialize,Deserialize,Debug)]
+pub struct StrengthConfig{
+	#[serde(default)]
+	pub profiles:HashMap<String,StrengthProfile>,
+	/**ユーザーidかホスト名から使うプロファイル名 ローカルのユーザーはインスタンスのホスト名*/
+	#[serde(default)]
+	pub users:HashMap<String,String>,
+	/**相手がbotの時のプロファイル名*/
+	pub bot:Option<String>,
+	/**相手が人間の時のプロファイル名*/
+	pub human:Option<String>,
+}
+/**ユーザーid,ホスト名,bot/人間の順に探して見つからなければdepth/perfect_search_depthを使う*/
+pub fn select(config:&ConfigFile,user:Option<&MiUser>)->StrengthProfile{
+	let strength=&config.strength;
+	let name=user.and_then(|user|{
+		let host=user.host_or_instance(&config.instance);
+		strength.users.get(&user.id)
+			.or_else(||host.and_then(|host|strength.users.get(&host)))
+			.or(if user.is_bot{
+				strength.bot.as_ref()
+			}else{
+				strength.human.as_ref()
+			})
+	});
+	if let Some(name)=name{
+		match strength.profiles.get(name){
+			Some(profile)=>return profile.clone(),
+			None=>tracing::warn!("unknown strength profile {}",name),
+		}
+	}
+	StrengthProfile{
+		depth:config.depth,
+		perfect_search_depth:config.perfect_search_depth,
+		handicap:0.0,
+	}
+}
+/**handicapの確率で最善手以外の合法手を選ぶ*/
+pub fn handicap_move(profile:&StrengthProfile,best:u16,legal_moves:&[u16])->u16{
+	let mut rng=rand::thread_rng();
+	if profile.handicap<=0.0||!rng.gen_bool(profile.handicap.min(1.0)){
+		return best;
+	}
+	let others=legal_moves.iter().filter(|pos|**pos!=best).collect::<Vec<_>>();
+	if others.is_empty(){
+		return best;
+	}
+	let pos=*others[rng.gen_range(0..others.len())];
+	tracing::info!(pos,best,"handicap move");
+	pos
+}
+/**stronger/weakerで変えられる段階の上限*/
+pub const MAX_OFFSET:i32=5;
+/**読みの深さをoffset段階変える 弱くする時は最善手以外も選ぶようにする*/
+pub fn adjust(profile:&StrengthProfile,offset:i32)->StrengthProfile{
+	let shift=|depth:u32|(depth as i32+offset).max(1) as u32;
+	let handicap=if offset<0{
+		(profile.handicap+0.1*(-offset) as f64).min(1.0)
+	}else{
+		profile.handicap
+	};
+	StrengthProfile{
+		depth:shift(profile.depth),
+		perfect_search_depth:shift(profile.perfect_search_depth),
+		handicap,
+	}
+}